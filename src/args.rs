@@ -1,38 +1,503 @@
-use clap::{Parser, Subcommand};
+use crate::ble::framing::{Framing, StreamFraming};
+use crate::ble::modbus::ModbusFraming;
+use crate::ble::server::Routing;
+use crate::ble::telegram::Command as TelegramCommand;
+use crate::config::Profile;
+use crate::protocol::Baudrate;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
 #[derive(Parser, Debug)]
 #[command(name = "ble")]
 pub struct CliArgs {
     #[clap(subcommand)]
     pub subcommand: Command,
+    #[arg(
+        long,
+        global = true,
+        help = "config profile from ~/.config/ble/config.toml or ./ble.toml"
+    )]
+    pub profile: Option<String>,
+    #[command(flatten)]
+    pub connection: ConnectionArgs,
+}
+
+/// Connection flags shared by every subcommand, they override the profile and environment.
+#[derive(Args, Debug, Default)]
+pub struct ConnectionArgs {
+    #[arg(long, global = true, help = "device address or name")]
+    pub device: Option<String>,
+    #[arg(long, global = true, help = "bluetooth adapter, e.g. hci1")]
+    pub adapter: Option<String>,
+    #[arg(long, global = true, help = "service UUID")]
+    pub service: Option<String>,
+    #[arg(long = "char", global = true, help = "testbench characteristic UUID")]
+    pub characteristic: Option<String>,
+    #[arg(long, global = true, help = "control point characteristic UUID")]
+    pub control_point: Option<String>,
+    #[arg(long, global = true, help = "response timeout in milliseconds")]
+    pub timeout: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        help = "times a request is repeated without response"
+    )]
+    pub retries: Option<u32>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "ATTEMPTS",
+        help = "times a dropped link is reconnected, 0 to give up right away"
+    )]
+    pub reconnect: Option<u32>,
+    #[arg(long, global = true, help = "select the device from a scan")]
+    pub interactive: bool,
+    #[arg(
+        long,
+        global = true,
+        help = "talk to the built-in simulator instead of a device"
+    )]
+    pub simulate: bool,
+    #[command(flatten)]
+    pub simulator: SimulatorArgs,
+}
+
+/// Settings of the device simulated with `--simulate`.
+#[derive(Args, Debug, Default)]
+#[command(next_help_heading = "Simulator")]
+pub struct SimulatorArgs {
+    #[arg(
+        long = "sim-device-type",
+        global = true,
+        value_parser = parse_number::<u16>,
+        help = "device type of the simulated device"
+    )]
+    pub device_type: Option<u16>,
+    #[arg(
+        long = "sim-serial",
+        global = true,
+        value_parser = parse_number::<u32>,
+        help = "serial number of the simulated device"
+    )]
+    pub serial: Option<u32>,
+    #[arg(
+        long = "sim-latency",
+        global = true,
+        help = "response delay in milliseconds"
+    )]
+    pub latency: Option<u64>,
+    #[arg(
+        long = "sim-drop-rate",
+        global = true,
+        value_parser = parse_probability,
+        help = "probability that a request is not answered"
+    )]
+    pub drop_rate: Option<f64>,
+    #[arg(
+        long = "sim-corrupt-rate",
+        global = true,
+        value_parser = parse_probability,
+        help = "probability that the CRC of a response is corrupted"
+    )]
+    pub corrupt_rate: Option<f64>,
+    #[arg(
+        long = "sim-seed",
+        global = true,
+        help = "seed for drops and corruption"
+    )]
+    pub seed: Option<u64>,
+}
+
+impl ConnectionArgs {
+    /// The flags as a profile, to be merged over the configured settings.
+    pub fn to_profile(&self) -> Profile {
+        let profile = Profile {
+            adapter: self.adapter.clone(),
+            service_uuid: self.service.clone(),
+            testbench_uuid: self.characteristic.clone(),
+            control_point_uuid: self.control_point.clone(),
+            timeout_ms: self.timeout,
+            simulate: self.simulate.then_some(true),
+            retries: self.retries,
+            reconnect_attempts: self.reconnect,
+            simulator_device_type: self.simulator.device_type,
+            simulator_serial: self.simulator.serial,
+            simulator_latency_ms: self.simulator.latency,
+            simulator_drop_rate: self.simulator.drop_rate,
+            simulator_corrupt_rate: self.simulator.corrupt_rate,
+            simulator_seed: self.simulator.seed,
+            ..Default::default()
+        };
+        match &self.device {
+            Some(device) => profile.with_device(device),
+            None => profile,
+        }
+    }
 }
 
 #[derive(Debug, Subcommand)]
 #[command(name = "ble", about = "CLI build for BlueSmile project")]
 pub enum Command {
     #[command(about = "runs a sequence of messages and reads the responses")]
-    Run {
-        iterations: usize,
-        delay: u64,
-    },
+    Run(RunArgs),
+    #[command(about = "replays the requests of a recording and compares the responses")]
+    Replay(ReplayArgs),
     #[command(about = "assign new passkey to ble-module")]
     AssignPasskey {
         passkey: Option<u32>,
     },
-    #[command(about = "assign new passkey to ble-module")]
+    #[command(about = "assign new baudrate to the serial port of ble-module")]
     AssignBaudrate {
-        baudrate: u32,
+        #[arg(help = baudrate_help())]
+        baudrate: Baudrate,
     },
     #[command(about = "decodes bytes to a telegram")]
     Decode {
+        #[arg(conflicts_with = "input")]
         bytes: Vec<String>,
-        #[arg(long, short)]
-        format: bool,
+        #[arg(long, short, help = "decode all telegrams in a file, - for stdin")]
+        input: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t)]
+        input_format: InputFormat,
+        #[arg(long, short, value_enum, default_value_t)]
+        output: OutputFormat,
+        #[arg(
+            long,
+            help = "report a wrong length byte as a warning instead of failing"
+        )]
+        lenient: bool,
+        #[arg(long, help = "extra subcommand definitions (.toml or .json)")]
+        registry: Option<PathBuf>,
+    },
+    #[command(about = "encodes fields to a telegram with CRC")]
+    Encode {
+        #[arg(long, value_parser = parse_number::<u16>)]
+        device_type: u16,
+        #[arg(long, value_parser = parse_number::<u32>, default_value = "0xFFFFFFFF")]
+        serial: u32,
+        #[arg(long, help = "read, write, execute or the command byte")]
+        command: TelegramCommand,
+        #[arg(long, help = "subcommand number or name from the registry")]
+        subcommand: String,
+        #[arg(long, default_value = "", help = "payload in hex, e.g. \"00 31\"")]
+        data: String,
+        #[arg(long, short, value_enum, default_value_t)]
+        output: EncodeFormat,
+        #[arg(long, help = "extra subcommand definitions (.toml or .json)")]
+        registry: Option<PathBuf>,
+    },
+    #[command(about = "reads a register by name or address and decodes it with the register map")]
+    ReadRegister {
+        register: String,
+        #[arg(long, default_value = "registers.toml")]
+        map: PathBuf,
+    },
+    #[command(about = "writes a value to a register from the register map")]
+    WriteRegister {
+        register: String,
+        #[arg(allow_negative_numbers = true)]
+        value: f64,
+        #[arg(long, default_value = "registers.toml")]
+        map: PathBuf,
     },
     #[command(about = "scan for devices")]
     Scan,
-    Explore,
+    Explore {
+        #[arg(long, help = "record all frames to a file, JSONL or pcapng (.pcapng)")]
+        record: Option<PathBuf>,
+    },
     #[command(about = "manage devices")]
     Devices,
     #[command(about = "Passes data between BT module and TCP")]
-    PassThrough,
+    PassThrough(PassThroughArgs),
+    #[command(about = "serves a simulated BlueSmile module over TCP or a Unix socket")]
+    Simulate {
+        #[arg(long, default_value = "127.0.0.1:5000", conflicts_with = "unix")]
+        tcp: String,
+        #[arg(long, help = "listen on a Unix socket instead of TCP")]
+        unix: Option<PathBuf>,
+        #[arg(long, value_parser = parse_number::<u16>, default_value = "3730")]
+        device_type: u16,
+        #[arg(long, value_parser = parse_number::<u32>, default_value = "12345678")]
+        serial: u32,
+        #[arg(long, default_value_t = 0, help = "response delay in milliseconds")]
+        latency: u64,
+        #[arg(long, value_parser = parse_probability, default_value_t = 0.0)]
+        drop_rate: f64,
+        #[arg(long, value_parser = parse_probability, default_value_t = 0.0)]
+        corrupt_rate: f64,
+        #[arg(long, help = "seed for drops and corruption")]
+        seed: Option<u64>,
+        #[command(flatten)]
+        framing: FramingArgs,
+    },
+    #[command(about = "sends telegrams to a pass-through or simulator over TCP")]
+    TcpClient(TcpClientArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    #[arg(
+        required_unless_present = "script",
+        help = "requests to send, or times the script runs"
+    )]
+    pub iterations: Option<usize>,
+    #[arg(help = "delay between requests in milliseconds, overrides the script default")]
+    pub delay: Option<u64>,
+    #[arg(long, help = "test sequence to run (.toml)")]
+    pub script: Option<PathBuf>,
+    #[arg(
+        long = "var",
+        value_name = "NAME=VALUE",
+        help = "set a script variable"
+    )]
+    pub variables: Vec<String>,
+    #[arg(long, help = "extra subcommand definitions (.toml or .json)")]
+    pub registry: Option<PathBuf>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        help = "json prints only the summary"
+    )]
+    pub summary: SummaryFormat,
+    #[arg(long, help = "write a test report, JUnit (.xml) or JSON (.json)")]
+    pub report: Option<PathBuf>,
+    #[arg(long, help = "record all frames to a file, JSONL or pcapng (.pcapng)")]
+    pub record: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct PassThroughArgs {
+    #[arg(
+        long,
+        default_value = "0.0.0.0:5000",
+        help = "address and port to listen on"
+    )]
+    pub bind: String,
+    #[arg(
+        long,
+        value_name = "LINK",
+        conflicts_with = "bind",
+        help = "bridge a pseudo-terminal instead of TCP, linked at LINK"
+    )]
+    pub pty: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "ADDR",
+        conflicts_with = "pty",
+        help = "also serve Modbus on this address and port, e.g. 0.0.0.0:502"
+    )]
+    pub modbus: Option<String>,
+    #[arg(
+        long,
+        requires = "modbus",
+        help = "mapping of Modbus registers and functions to telegrams (.toml or .json)"
+    )]
+    pub modbus_map: Option<PathBuf>,
+    #[arg(
+        long,
+        requires = "modbus",
+        default_value_t,
+        help = "tcp (MBAP header) or rtu (RTU frames with CRC over TCP)"
+    )]
+    pub modbus_framing: ModbusFraming,
+    #[command(flatten)]
+    pub framing: FramingArgs,
+    #[arg(
+        long,
+        value_name = "ROUTING",
+        default_value_t,
+        help = "who gets notifications that answer no request: all, owner (of the latest request) or none"
+    )]
+    pub notifications: Routing,
+    #[arg(long, help = "extra subcommand definitions (.toml or .json)")]
+    pub registry: Option<PathBuf>,
+    #[arg(long, help = "record all frames to a file, JSONL or pcapng (.pcapng)")]
+    pub record: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct TcpClientArgs {
+    #[arg(help = "telegrams in hex, read from stdin one per line when absent")]
+    pub telegrams: Vec<String>,
+    #[arg(
+        long,
+        default_value = "127.0.0.1:5000",
+        help = "address and port of the server"
+    )]
+    pub connect: String,
+    #[command(flatten)]
+    pub framing: FramingArgs,
+    #[arg(
+        long,
+        default_value_t = 1500,
+        help = "response timeout in milliseconds"
+    )]
+    pub timeout: u64,
+    #[arg(long, help = "extra subcommand definitions (.toml or .json)")]
+    pub registry: Option<PathBuf>,
+}
+
+/// Framing of the pass-through TCP protocol, shared by its servers and clients.
+#[derive(Args, Debug)]
+pub struct FramingArgs {
+    #[arg(
+        long,
+        default_value_t,
+        help = "framing of requests and responses: length-prefixed, raw, hex or json"
+    )]
+    pub framing: Framing,
+    #[arg(long, help = "framing of responses, when it differs from --framing")]
+    pub response_framing: Option<Framing>,
+}
+
+impl FramingArgs {
+    pub fn stream_framing(&self) -> StreamFraming {
+        StreamFraming {
+            request: self.framing,
+            response: self.response_framing.unwrap_or(self.framing),
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ReplayArgs {
+    #[arg(help = "JSONL recording made with --record")]
+    pub file: PathBuf,
+    #[arg(
+        long,
+        default_value_t = 1.0,
+        value_parser = parse_speed,
+        help = "timing scale, 2 replays twice as fast"
+    )]
+    pub speed: f64,
+    #[arg(
+        long,
+        conflicts_with = "speed",
+        help = "fixed delay between requests in milliseconds instead of the recorded timing"
+    )]
+    pub delay: Option<u64>,
+    #[arg(
+        long,
+        help = "replay to a pass-through server at this address instead of the device"
+    )]
+    pub tcp: Option<String>,
+    #[command(flatten)]
+    pub framing: FramingArgs,
+    #[arg(long, help = "extra subcommand definitions (.toml or .json)")]
+    pub registry: Option<PathBuf>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        help = "json prints only the summary"
+    )]
+    pub summary: SummaryFormat,
+    #[arg(long, help = "write a test report, JUnit (.xml) or JSON (.json)")]
+    pub report: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+    Csv,
+    Rust,
+}
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum SummaryFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum InputFormat {
+    /// One telegram in hex per line
+    #[default]
+    Lines,
+    /// Continuous hex dump, split into telegrams by their length byte
+    Hex,
+    /// Continuous binary dump, split into telegrams by their length byte
+    Binary,
+}
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum EncodeFormat {
+    /// Space separated hex bytes
+    #[default]
+    Hex,
+    /// C array initializer
+    C,
+    /// Rust array literal
+    Rust,
+}
+
+/// Parses a probability between 0 and 1.
+pub fn parse_probability(s: &str) -> Result<f64, String> {
+    let value: f64 = s.parse().map_err(|_| format!("{} is not a number", s))?;
+    if !(0.0..=1.0).contains(&value) {
+        return Err(format!("{} is not between 0 and 1", s));
+    }
+    Ok(value)
+}
+
+/// Lists the supported baudrates.
+fn baudrate_help() -> String {
+    let rates: Vec<_> = Baudrate::all().map(|b| b.to_string()).collect();
+    format!("one of {}", rates.join(", "))
+}
+
+/// Parses a positive timing scale.
+pub fn parse_speed(s: &str) -> Result<f64, String> {
+    let value: f64 = s.parse().map_err(|_| format!("{} is not a number", s))?;
+    if !(value > 0.0 && value.is_finite()) {
+        return Err(format!("{} is not a positive number", s));
+    }
+    Ok(value)
+}
+
+/// Parses a decimal or `0x` prefixed hexadecimal number.
+pub fn parse_number<T: TryFrom<u64>>(s: &str) -> Result<T, String> {
+    let value = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse::<u64>(),
+    }
+    .map_err(|e| e.to_string())?;
+    T::try_from(value).map_err(|_| format!("{} is out of range", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_baudrate_help() {
+        let mut command = CliArgs::command();
+        let help = command
+            .find_subcommand_mut("assign-baudrate")
+            .unwrap()
+            .render_help()
+            .to_string();
+        assert!(help.contains("baudrate to the serial port"));
+        assert!(help.contains("one of 2400, 4800, 9600, 14400, 19200, 28800, 38400, 57600, 115200"));
+        assert!(CliArgs::try_parse_from(["ble", "assign-baudrate", "1200"]).is_err());
+    }
+
+    #[test]
+    fn test_write_negative_value() {
+        let args = CliArgs::try_parse_from(["ble", "write-register", "0X0031", "-1.5"]).unwrap();
+        match args.subcommand {
+            Command::WriteRegister {
+                register, value, ..
+            } => {
+                assert_eq!(register, "0X0031");
+                assert_eq!(value, -1.5);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,405 @@
+use std::{error::Error, fmt::Display};
+
+use futures::FutureExt;
+use serde::Serialize;
+use tokio::time::{sleep, timeout_at, Duration, Instant};
+
+use crate::ble::{
+    telegram::{Telegram, TelegramError, ANY_DEVICE_TYPE, ANY_SERIAL_NUMBER},
+    transport::TelegramTransport,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RequestOptions {
+    /// Time to write the request and receive a matching response, per attempt.
+    pub timeout: Duration,
+    /// Attempts after the first one.
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every following retry.
+    pub backoff: Duration,
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        RequestOptions {
+            timeout: Duration::from_millis(1500),
+            retries: 0,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+/// What to do with notifications that don't answer the current request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StrayPolicy {
+    Discard,
+    /// Keep them until [`Client::take_strays`].
+    #[default]
+    Report,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RequestError {
+    /// No matching response arrived in any attempt.
+    Timeout { attempts: u32 },
+    /// Frames arrived but failed to decode, the last error is kept.
+    BadFrame { error: TelegramError, attempts: u32 },
+    /// The transport closed before a response arrived.
+    Closed,
+    /// Writing the request failed.
+    Send(String),
+}
+
+impl Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Timeout { attempts } => {
+                write!(f, "no response after {} attempt(s)", attempts)
+            }
+            Self::BadFrame { error, attempts } => write!(
+                f,
+                "no valid response after {} attempt(s): {}",
+                attempts, error
+            ),
+            Self::Closed => write!(f, "transport closed"),
+            Self::Send(e) => write!(f, "failed to send request: {}", e),
+        }
+    }
+}
+
+impl Error for RequestError {}
+
+/// Counters over all requests of a [`Client`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct ClientStats {
+    /// Requests written, retries included.
+    pub attempts: usize,
+    pub retries: usize,
+    pub strays: usize,
+    /// Frames that failed to decode, checksum errors included. Corrupted frames that arrive
+    /// while the framer is still resynchronizing are only counted once.
+    pub bad_frames: usize,
+    pub bad_checksums: usize,
+    pub bytes_sent: usize,
+    /// Bytes of the decoded telegrams, strays included.
+    pub bytes_received: usize,
+}
+
+/// Whether `response` answers `request`: the same subcommand from the addressed device.
+pub fn is_response(request: &Telegram, response: &Telegram) -> bool {
+    (request.device_type == ANY_DEVICE_TYPE || request.device_type == response.device_type)
+        && (request.serial_number == ANY_SERIAL_NUMBER
+            || request.serial_number == response.serial_number)
+        && request.subcommand == response.subcommand
+}
+
+/// Sends requests one at a time and matches the notifications to them.
+///
+/// Telegrams carry no sequence number, so a late response can only be told apart from the
+/// next one when the requests differ. Notifications that are already waiting when a request is
+/// sent are treated as strays.
+pub struct Client<T> {
+    transport: T,
+    options: RequestOptions,
+    stray_policy: StrayPolicy,
+    strays: Vec<Telegram>,
+    stats: ClientStats,
+}
+
+impl<T: TelegramTransport> Client<T> {
+    pub fn new(transport: T, options: RequestOptions) -> Self {
+        Client {
+            transport,
+            options,
+            stray_policy: StrayPolicy::default(),
+            strays: Vec::new(),
+            stats: ClientStats::default(),
+        }
+    }
+
+    pub fn with_stray_policy(mut self, stray_policy: StrayPolicy) -> Self {
+        self.stray_policy = stray_policy;
+        self
+    }
+
+    pub fn options(&self) -> RequestOptions {
+        self.options
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    pub fn stats(&self) -> ClientStats {
+        self.stats
+    }
+
+    /// The stray notifications received since the last call.
+    pub fn take_strays(&mut self) -> Vec<Telegram> {
+        std::mem::take(&mut self.strays)
+    }
+
+    /// The stray notifications kept so far, without taking them.
+    pub fn strays(&self) -> &[Telegram] {
+        &self.strays
+    }
+
+    /// Waits for a notification while no request is outstanding, `None` once the transport is
+    /// closed. It counts as a stray but isn't kept. Cancelling the wait loses no notification.
+    pub async fn notification(&mut self) -> Option<Result<Telegram, TelegramError>> {
+        let notification = self.transport.recv().await?;
+        match &notification {
+            Ok(telegram) => {
+                self.received(telegram);
+                self.stats.strays += 1;
+            }
+            Err(error) => self.bad_frame(*error),
+        }
+        Some(notification)
+    }
+
+    pub async fn request(&mut self, telegram: &Telegram) -> Result<Telegram, RequestError> {
+        self.request_with(telegram, self.options).await
+    }
+
+    /// Like [`Client::request`] with options for this request only.
+    pub async fn request_with(
+        &mut self,
+        telegram: &Telegram,
+        options: RequestOptions,
+    ) -> Result<Telegram, RequestError> {
+        let mut last_error = None;
+        for attempt in 0..=options.retries {
+            if attempt > 0 {
+                self.stats.retries += 1;
+                let factor = 1u32 << (attempt - 1).min(16);
+                sleep(options.backoff.saturating_mul(factor)).await;
+            }
+
+            self.drain();
+            let deadline = Instant::now() + options.timeout;
+            match timeout_at(deadline, self.transport.send(telegram)).await {
+                Ok(sent) => sent.map_err(|e| RequestError::Send(e.to_string()))?,
+                // Writing waits while a dropped link reconnects, the attempt times out like one
+                // without response.
+                Err(_) => {
+                    self.stats.attempts += 1;
+                    continue;
+                }
+            }
+            self.stats.attempts += 1;
+            self.stats.bytes_sent += telegram.to_bytes().map_or(0, |b| b.len());
+
+            match self.await_response(telegram, deadline).await {
+                Ok(response) => return Ok(response),
+                Err(RequestError::BadFrame { error, .. }) => last_error = Some(error),
+                Err(RequestError::Timeout { .. }) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let attempts = options.retries + 1;
+        Err(match last_error {
+            Some(error) => RequestError::BadFrame { error, attempts },
+            None => RequestError::Timeout { attempts },
+        })
+    }
+
+    /// Waits for a response to `request` until `deadline`, the attempts of the error are left
+    /// at 0.
+    async fn await_response(
+        &mut self,
+        request: &Telegram,
+        deadline: Instant,
+    ) -> Result<Telegram, RequestError> {
+        let mut bad_frame = None;
+        loop {
+            match timeout_at(deadline, self.transport.recv()).await {
+                Ok(Some(Ok(response))) => {
+                    self.received(&response);
+                    if is_response(request, &response) {
+                        return Ok(response);
+                    }
+                    self.stray(response);
+                }
+                Ok(Some(Err(error))) => {
+                    self.bad_frame(error);
+                    bad_frame = Some(error);
+                }
+                Ok(None) => return Err(RequestError::Closed),
+                Err(_) => {
+                    return Err(match bad_frame {
+                        Some(error) => RequestError::BadFrame { error, attempts: 0 },
+                        None => RequestError::Timeout { attempts: 0 },
+                    })
+                }
+            }
+        }
+    }
+
+    /// Takes the notifications that arrived while no request was waiting.
+    fn drain(&mut self) {
+        while let Some(Some(notification)) = self.transport.recv().now_or_never() {
+            match notification {
+                Ok(stray) => {
+                    self.received(&stray);
+                    self.stray(stray);
+                }
+                Err(error) => self.bad_frame(error),
+            }
+        }
+    }
+
+    fn received(&mut self, telegram: &Telegram) {
+        self.stats.bytes_received += telegram.to_bytes().map_or(0, |b| b.len());
+    }
+
+    fn bad_frame(&mut self, error: TelegramError) {
+        self.stats.bad_frames += 1;
+        if matches!(error, TelegramError::BadChecksum { .. }) {
+            self.stats.bad_checksums += 1;
+        }
+    }
+
+    fn stray(&mut self, telegram: Telegram) {
+        self.stats.strays += 1;
+        if self.stray_policy == StrayPolicy::Report {
+            self.strays.push(telegram);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::{
+        prefab::greet,
+        simulator::{Simulator, SimulatorConfig},
+        transport::LoopbackTransport,
+    };
+    use crate::protocol::GREET;
+
+    fn request(subcommand: u8) -> Telegram {
+        Telegram {
+            subcommand,
+            ..greet()
+        }
+    }
+
+    fn options(retries: u32) -> RequestOptions {
+        RequestOptions {
+            timeout: Duration::from_millis(50),
+            retries,
+            backoff: Duration::from_millis(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_matches_response() {
+        let (transport, mut device) = LoopbackTransport::pair();
+        let mut client = Client::new(transport, options(0));
+
+        let mut late = request(101);
+        late.serial_number = 1;
+        device.send(&late).unwrap();
+
+        let respond = async {
+            let t = device.recv().await.unwrap();
+            let mut other = t.clone();
+            other.subcommand = 206;
+            device.send(&other).unwrap();
+            let mut response = t.clone();
+            response.serial_number = 1;
+            device.send(&response).unwrap();
+        };
+        let greet = request(GREET);
+        let (response, _) = futures::join!(client.request(&greet), respond);
+
+        assert_eq!(response.unwrap().subcommand, GREET);
+        let strays = client.take_strays();
+        assert_eq!(strays.len(), 2);
+        assert_eq!(strays[0].subcommand, 101);
+        assert_eq!(strays[1].subcommand, 206);
+        assert_eq!(client.stats().strays, 2);
+        assert_eq!(client.stats().bytes_sent, 11);
+    }
+
+    #[tokio::test]
+    async fn test_retries() {
+        let config = SimulatorConfig {
+            drop_rate: 1.0,
+            ..Default::default()
+        };
+        let mut client = Client::new(Simulator::new(config).transport(), options(2));
+        assert_eq!(
+            client.request(&request(GREET)).await,
+            Err(RequestError::Timeout { attempts: 3 })
+        );
+        assert_eq!(client.stats().retries, 2);
+
+        let config = SimulatorConfig {
+            corrupt_rate: 1.0,
+            ..Default::default()
+        };
+        let mut client = Client::new(Simulator::new(config).transport(), options(1));
+        assert!(matches!(
+            client.request(&request(GREET)).await,
+            Err(RequestError::BadFrame {
+                error: TelegramError::BadChecksum { .. },
+                attempts: 2
+            })
+        ));
+        assert!(client.stats().bad_checksums >= 1);
+    }
+
+    /// A transport whose writes never complete, like one waiting for a link to reconnect.
+    struct Stalled;
+
+    impl TelegramTransport for Stalled {
+        async fn send(&mut self, _: &Telegram) -> anyhow::Result<()> {
+            std::future::pending().await
+        }
+
+        async fn recv(&mut self) -> Option<Result<Telegram, TelegramError>> {
+            std::future::pending().await
+        }
+
+        async fn send_control(&mut self, _: &[u8]) -> anyhow::Result<()> {
+            std::future::pending().await
+        }
+
+        async fn recv_control(&mut self) -> Option<Vec<u8>> {
+            std::future::pending().await
+        }
+
+        async fn close(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_timeout() {
+        let mut client = Client::new(Stalled, options(1));
+        assert_eq!(
+            client.request(&request(GREET)).await,
+            Err(RequestError::Timeout { attempts: 2 })
+        );
+        assert_eq!(client.stats().attempts, 2);
+        assert_eq!(client.stats().bytes_sent, 0);
+    }
+
+    #[tokio::test]
+    async fn test_closed() {
+        let (transport, device) = LoopbackTransport::pair();
+        let mut client = Client::new(transport, options(0));
+        drop(device);
+        assert!(matches!(
+            client.request(&request(GREET)).await,
+            Err(RequestError::Send(_))
+        ));
+    }
+}
@@ -0,0 +1,281 @@
+use std::{path::Path, pin::Pin};
+
+use anyhow::{anyhow, bail, Result};
+use bluer::{Adapter, Device, DeviceEvent, DeviceProperty, Uuid};
+use futures::{Stream, StreamExt};
+use tokio::time::{sleep, Duration};
+
+use crate::ble::{
+    find_characteristic, find_selected_device, find_service, open_adapter,
+    recorder::{Recorder, RecordingTransport},
+    simulator::Simulator,
+    supervisor::{Link, SupervisedTransport},
+    telegram::{Telegram, TelegramError},
+    transport::{GattTransport, LoopbackTransport, TelegramTransport},
+};
+use crate::config::{Config, DeviceSelector};
+
+/// The characteristics a subcommand talks to.
+#[derive(Debug, Clone, Copy)]
+pub struct Channels {
+    pub testbench: bool,
+    pub control_point: bool,
+}
+
+impl Channels {
+    pub const TESTBENCH: Channels = Channels {
+        testbench: true,
+        control_point: false,
+    };
+    pub const CONTROL_POINT: Channels = Channels {
+        testbench: false,
+        control_point: true,
+    };
+    pub const ALL: Channels = Channels {
+        testbench: true,
+        control_point: true,
+    };
+}
+
+pub enum Transport {
+    Gatt(Box<SupervisedTransport<GattLink>>),
+    Simulated(LoopbackTransport),
+}
+
+impl TelegramTransport for Transport {
+    async fn send(&mut self, telegram: &Telegram) -> Result<()> {
+        match self {
+            Transport::Gatt(t) => t.send(telegram).await,
+            Transport::Simulated(t) => t.send(telegram).await,
+        }
+    }
+
+    async fn recv(&mut self) -> Option<Result<Telegram, TelegramError>> {
+        match self {
+            Transport::Gatt(t) => t.recv().await,
+            Transport::Simulated(t) => t.recv().await,
+        }
+    }
+
+    async fn send_control(&mut self, command: &[u8]) -> Result<()> {
+        match self {
+            Transport::Gatt(t) => t.send_control(command).await,
+            Transport::Simulated(t) => t.send_control(command).await,
+        }
+    }
+
+    async fn recv_control(&mut self) -> Option<Vec<u8>> {
+        match self {
+            Transport::Gatt(t) => t.recv_control().await,
+            Transport::Simulated(t) => t.recv_control().await,
+        }
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        match self {
+            Transport::Gatt(t) => t.close().await,
+            Transport::Simulated(t) => t.close().await,
+        }
+    }
+
+    fn keep_notifications(&mut self) -> bool {
+        match self {
+            Transport::Gatt(t) => t.keep_notifications(),
+            Transport::Simulated(t) => t.keep_notifications(),
+        }
+    }
+
+    fn take_notifications(&mut self) -> Vec<Vec<u8>> {
+        match self {
+            Transport::Gatt(t) => t.take_notifications(),
+            Transport::Simulated(t) => t.take_notifications(),
+        }
+    }
+}
+
+/// The GATT characteristics of a device, resolved by UUID whenever it (re)connects.
+pub struct GattLink {
+    device: Device,
+    events: Pin<Box<dyn Stream<Item = DeviceEvent>>>,
+    service: Uuid,
+    testbench: Option<Uuid>,
+    control_point: Option<Uuid>,
+}
+
+impl GattLink {
+    pub async fn new(
+        device: Device,
+        service: Uuid,
+        testbench: Option<Uuid>,
+        control_point: Option<Uuid>,
+    ) -> Result<Self> {
+        let events = Box::pin(device.events().await?);
+        Ok(GattLink {
+            device,
+            events,
+            service,
+            testbench,
+            control_point,
+        })
+    }
+
+    /// Resolves the service and characteristics and subscribes to their notifications.
+    async fn open(&mut self) -> Result<GattTransport> {
+        sleep(Duration::from_secs(1)).await;
+
+        let service = find_service(&self.device, self.service)
+            .await?
+            .ok_or_else(|| anyhow!("service {} not found", self.service))?;
+
+        let mut transport = GattTransport::new();
+        if let Some(uuid) = self.testbench {
+            let char = find_characteristic(&service, uuid)
+                .await?
+                .ok_or_else(|| anyhow!("characteristic {} not found", uuid))?;
+            transport = transport.with_testbench(char).await?;
+        }
+        if let Some(uuid) = self.control_point {
+            let char = find_characteristic(&service, uuid)
+                .await?
+                .ok_or_else(|| anyhow!("control point {} not found", uuid))?;
+            transport = transport.with_control_point(char).await?;
+        }
+        Ok(transport)
+    }
+}
+
+impl Link for GattLink {
+    type Transport = GattTransport;
+
+    async fn disconnected(&mut self) {
+        while let Some(event) = self.events.next().await {
+            if let DeviceEvent::PropertyChanged(DeviceProperty::Connected(false)) = event {
+                return;
+            }
+        }
+    }
+
+    async fn is_connected(&mut self) -> bool {
+        self.device.is_connected().await.unwrap_or(false)
+    }
+
+    async fn connect(&mut self) -> Result<GattTransport> {
+        self.events = Box::pin(self.device.events().await?);
+        if !self.device.is_connected().await? {
+            self.device.connect().await?;
+        }
+        self.open().await
+    }
+}
+
+/// An open link to the configured device, or to the simulator when `simulate` is set. The
+/// adapter and device are absent for the simulator. A dropped link to a device is
+/// reconnected following [`Config::reconnect_policy`]. Progress is logged to stderr, so
+/// subcommands can keep stdout machine readable.
+pub struct Connection {
+    pub adapter: Option<Adapter>,
+    pub device: Option<Device>,
+    pub transport: Transport,
+}
+
+impl Connection {
+    pub async fn open(config: &Config, channels: Channels) -> Result<Connection> {
+        Self::open_with(config, Config::device, channels).await
+    }
+
+    /// Like [`Connection::open`], with the device chosen by `select`.
+    pub async fn open_with(
+        config: &Config,
+        select: fn(&Config) -> Result<DeviceSelector>,
+        channels: Channels,
+    ) -> Result<Connection> {
+        if config.simulate() {
+            eprintln!("Using simulated device");
+            return Ok(Connection {
+                adapter: None,
+                device: None,
+                transport: Transport::Simulated(
+                    Simulator::new(config.simulator_config()?).transport(),
+                ),
+            });
+        }
+
+        let device = select(config)?;
+        let service_uuid = config.service_uuid()?;
+        let testbench_uuid = channels
+            .testbench
+            .then(|| config.testbench_uuid())
+            .transpose()?;
+        let control_point_uuid = channels
+            .control_point
+            .then(|| config.control_point_uuid())
+            .transpose()?;
+
+        let session = bluer::Session::new().await?;
+        let adapter = open_adapter(&session, config.adapter()).await?;
+        adapter.set_powered(true).await?;
+
+        let dev = find_selected_device(&adapter, &device, config.scan_timeout())
+            .await?
+            .ok_or_else(|| anyhow!("Couldn't find device {:?}", device))?;
+
+        if !dev.is_connected().await? {
+            eprintln!("connecting...");
+            dev.connect().await?;
+        }
+        eprintln!("Connected");
+
+        if !dev.is_paired().await? {
+            eprintln!("Pairing...");
+            if let Err(e) = dev.pair().await {
+                dev.disconnect().await?;
+                bail!("Failed to pair: {}", e);
+            }
+        }
+        eprintln!("Paired");
+
+        let mut link = GattLink::new(
+            dev.clone(),
+            service_uuid,
+            testbench_uuid,
+            control_point_uuid,
+        )
+        .await?;
+        let transport = link.open().await?;
+
+        Ok(Connection {
+            adapter: Some(adapter),
+            device: Some(dev),
+            transport: Transport::Gatt(Box::new(SupervisedTransport::new(
+                link,
+                transport,
+                config.reconnect_policy(),
+            ))),
+        })
+    }
+
+    /// The transport, recording its traffic to `path` when given.
+    pub fn recorded(
+        &mut self,
+        config: &Config,
+        path: Option<&Path>,
+    ) -> Result<RecordingTransport<&mut Transport>> {
+        let recorder = path.map(Recorder::create).transpose()?;
+        // The simulator runs without configured UUIDs, those are recorded as the nil UUID.
+        Ok(RecordingTransport::new(
+            &mut self.transport,
+            recorder,
+            config.testbench_uuid().unwrap_or_default(),
+            config.control_point_uuid().unwrap_or_default(),
+        ))
+    }
+
+    pub async fn disconnect(mut self) -> Result<()> {
+        self.transport.close().await?;
+        if let Some(dev) = self.device {
+            dev.disconnect().await?;
+            eprintln!("disconnected");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::{anyhow, bail, Result};
+use tokio::time::{timeout, Duration};
+
+use crate::{
+    ble::transport::TelegramTransport,
+    protocol::{Baudrate, CommandType, ControlCommand, ControlResponse},
+};
+
+/// Writes a command to the control point and returns the echo.
+pub async fn request<T: TelegramTransport>(
+    transport: &mut T,
+    command: ControlCommand,
+    response_timeout: Duration,
+) -> Result<ControlResponse> {
+    transport.send_control(&command.serialize()).await?;
+
+    match timeout(response_timeout, transport.recv_control()).await {
+        Ok(Some(v)) => ControlResponse::parse(&v)
+            .ok_or_else(|| anyhow!("control point response too short: {:?}", v)),
+        Ok(None) => Err(anyhow!("End of messages")),
+        Err(_) => Err(anyhow!("Timeout while reading response")),
+    }
+}
+
+pub async fn assign_baudrate<T: TelegramTransport>(
+    transport: &mut T,
+    baudrate: Baudrate,
+    response_timeout: Duration,
+) -> Result<()> {
+    let retrieved = request(transport, baudrate.command(), response_timeout).await?;
+    if retrieved.baudrate() != Some(baudrate) {
+        bail!(
+            "baudrate failed to assign: retrieved wrong baudrate: {:#08x}",
+            retrieved.0
+        );
+    }
+    Ok(())
+}
+
+pub async fn assign_passkey<T: TelegramTransport>(
+    transport: &mut T,
+    passkey: u32,
+    response_timeout: Duration,
+) -> Result<()> {
+    let command = ControlCommand::new(CommandType::PASSKEY, passkey.to_le_bytes());
+    let retrieved = request(transport, command, response_timeout).await?;
+    if retrieved.0 != passkey {
+        bail!(
+            "passkey failed to assign: retrieved wrong passkey: {}",
+            retrieved.0
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::transport::LoopbackTransport;
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    #[tokio::test]
+    async fn test_assign_baudrate() {
+        let (mut transport, mut device) = LoopbackTransport::pair();
+        let baudrate = |rate| Baudrate::try_from(rate).unwrap();
+
+        device.send_control(vec![0x03, 0x68, 0x00, 0x00]).unwrap();
+        assign_baudrate(&mut transport, baudrate(9600), TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(
+            device.recv_control().await.unwrap(),
+            ControlCommand::new(CommandType::BAUDRATE, 9600u32.to_le_bytes()).serialize()
+        );
+
+        device.send_control(vec![0x0b, 0xa0, 0x01, 0x00]).unwrap();
+        assign_baudrate(&mut transport, baudrate(2400), TIMEOUT)
+            .await
+            .unwrap();
+
+        device.send_control(vec![0x03, 0x68, 0x00, 0x00]).unwrap();
+        assert!(assign_baudrate(&mut transport, baudrate(4800), TIMEOUT)
+            .await
+            .is_err());
+
+        assert!(assign_baudrate(&mut transport, baudrate(9600), TIMEOUT)
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("Timeout"));
+    }
+
+    #[tokio::test]
+    async fn test_assign_passkey() {
+        let (mut transport, device) = LoopbackTransport::pair();
+
+        device
+            .send_control(123456u32.to_le_bytes().to_vec())
+            .unwrap();
+        assign_passkey(&mut transport, 123456, TIMEOUT)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+use std::fmt::Display;
+
+use crate::ble::telegram::{format_hex, parse_hex, Telegram};
+
+/// The response a step of a sequence expects.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expectation {
+    /// Exactly this telegram.
+    Exact(Telegram),
+    /// The bytes of the response, `None` matches any byte. With `open` set the response may
+    /// continue after the pattern.
+    Pattern {
+        bytes: Vec<Option<u8>>,
+        open: bool,
+    },
+    Subcommand(u8),
+    /// The payload starts with these bytes.
+    DataPrefix(Vec<u8>),
+    /// The response bytes under `mask` equal those of `value`, bytes beyond the mask are
+    /// ignored.
+    Mask {
+        value: Vec<u8>,
+        mask: Vec<u8>,
+    },
+}
+
+impl Expectation {
+    /// Parses the bytes of a complete telegram.
+    pub fn exact(text: &str) -> Result<Self, String> {
+        let bytes = parse_hex(text).map_err(|t| format!("invalid hex byte {}", t))?;
+        let telegram = Telegram::from_bytes(&bytes).map_err(|e| e.to_string())?;
+        Ok(Expectation::Exact(telegram))
+    }
+
+    /// Parses a pattern of hex bytes where `??` matches any byte and a trailing `*` matches
+    /// the rest of the response, e.g. `0E 92 ?? ?? ?? ?? 0D 01 CC *`.
+    pub fn pattern(text: &str) -> Result<Self, String> {
+        let mut tokens: Vec<&str> = text
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|t| !t.is_empty())
+            .collect();
+        let open = tokens.last() == Some(&"*");
+        if open {
+            tokens.pop();
+        }
+
+        let mut bytes = Vec::new();
+        for token in tokens {
+            if token == "??" {
+                bytes.push(None);
+                continue;
+            }
+            let byte = parse_hex(token).map_err(|t| format!("invalid pattern byte {}", t))?;
+            if byte.len() != 1 {
+                return Err(format!("invalid pattern byte {}", token));
+            }
+            bytes.push(Some(byte[0]));
+        }
+        Ok(Expectation::Pattern { bytes, open })
+    }
+
+    /// Parses `value` and `mask` as hex bytes of equal length.
+    pub fn mask(value: &str, mask: &str) -> Result<Self, String> {
+        let value = parse_hex(value).map_err(|t| format!("invalid hex byte {}", t))?;
+        let mask = parse_hex(mask).map_err(|t| format!("invalid hex byte {}", t))?;
+        if value.len() != mask.len() {
+            return Err(format!(
+                "value has {} bytes, mask has {}",
+                value.len(),
+                mask.len()
+            ));
+        }
+        Ok(Expectation::Mask { value, mask })
+    }
+
+    /// `Ok` when `response` meets the expectation, otherwise what didn't match.
+    pub fn check(&self, response: &Telegram) -> Result<(), String> {
+        let actual = response.to_bytes().map_err(|e| e.to_string())?;
+        let matches = match self {
+            Expectation::Exact(expected) => expected == response,
+            Expectation::Pattern { bytes, open } => {
+                (actual.len() == bytes.len() || (*open && actual.len() >= bytes.len()))
+                    && bytes
+                        .iter()
+                        .zip(&actual)
+                        .all(|(expected, actual)| expected.is_none_or(|e| e == *actual))
+            }
+            Expectation::Subcommand(subcommand) => response.subcommand == *subcommand,
+            Expectation::DataPrefix(prefix) => response.data.starts_with(prefix),
+            Expectation::Mask { value, mask } => {
+                actual.len() >= mask.len()
+                    && mask
+                        .iter()
+                        .zip(value)
+                        .zip(&actual)
+                        .all(|((mask, value), actual)| actual & mask == value & mask)
+            }
+        };
+        if matches {
+            return Ok(());
+        }
+        let mut mismatch = format!("expected {}, got {}", self, format_hex(&actual));
+        if let Expectation::Exact(expected) = self {
+            let expected = expected.to_bytes().unwrap_or_default();
+            let first = (0..expected.len().max(actual.len()))
+                .find(|&i| expected.get(i) != actual.get(i))
+                .unwrap_or_default();
+            mismatch += &format!(", first difference at byte {}", first);
+        }
+        Err(mismatch)
+    }
+}
+
+impl Display for Expectation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expectation::Exact(telegram) => {
+                write!(
+                    f,
+                    "{}",
+                    format_hex(&telegram.to_bytes().unwrap_or_default())
+                )
+            }
+            Expectation::Pattern { bytes, open } => {
+                let mut tokens: Vec<String> = bytes
+                    .iter()
+                    .map(|b| b.map_or("??".to_string(), |b| format!("{:02X}", b)))
+                    .collect();
+                if *open {
+                    tokens.push("*".to_string());
+                }
+                write!(f, "{}", tokens.join(" "))
+            }
+            Expectation::Subcommand(subcommand) => write!(f, "subcommand {}", subcommand),
+            Expectation::DataPrefix(prefix) => {
+                write!(f, "data starting with {}", format_hex(prefix))
+            }
+            Expectation::Mask { value, mask } => {
+                write!(f, "{} under mask {}", format_hex(value), format_hex(mask))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::prefab;
+
+    /// The simulator's answer to the greet request.
+    fn greet() -> Telegram {
+        Telegram {
+            serial_number: 12345678,
+            data: b"BlueSmile".to_vec(),
+            ..prefab::greet()
+        }
+    }
+
+    #[test]
+    fn test_exact() {
+        let bytes = format_hex(&greet().to_bytes().unwrap());
+        let expectation = Expectation::exact(&bytes).unwrap();
+        assert_eq!(expectation.check(&greet()), Ok(()));
+
+        let mut other = greet();
+        other.data.pop();
+        let error = expectation.check(&other).unwrap_err();
+        assert!(error.ends_with("first difference at byte 6"), "{}", error);
+        assert!(Expectation::exact("0E 92").is_err());
+    }
+
+    #[test]
+    fn test_pattern() {
+        let open = Expectation::pattern("0E 92 ?? ?? ?? ?? 0D 01 CC *").unwrap();
+        assert_eq!(open.check(&greet()), Ok(()));
+
+        let closed = Expectation::pattern("0E 92 ?? ?? ?? ?? 0D 01 CC").unwrap();
+        let error = closed.check(&greet()).unwrap_err();
+        assert!(error.starts_with("expected 0E 92 ?? ?? ?? ?? 0D 01 CC, got 0E 92 00 BC"));
+
+        let mut other = greet();
+        other.subcommand = 101;
+        assert!(open.check(&other).is_err());
+        assert!(Expectation::pattern("0E 9G").is_err());
+        assert!(Expectation::pattern("0E92").is_err());
+    }
+
+    #[test]
+    fn test_fields_and_mask() {
+        assert_eq!(Expectation::Subcommand(204).check(&greet()), Ok(()));
+        assert!(Expectation::Subcommand(101).check(&greet()).is_err());
+        assert_eq!(
+            Expectation::DataPrefix(b"Blue".to_vec()).check(&greet()),
+            Ok(())
+        );
+        assert!(Expectation::DataPrefix(b"Red".to_vec())
+            .check(&greet())
+            .is_err());
+
+        // Device type 3730 in any device of the 0x0E00 family, serial ignored.
+        let family = Expectation::mask("0E 00 00 00 00 00 0D", "FF 00 00 00 00 00 FF").unwrap();
+        assert_eq!(family.check(&greet()), Ok(()));
+        let mut other = greet();
+        other.data.pop();
+        assert_eq!(
+            family.check(&other).unwrap_err(),
+            format!(
+                "expected 0E 00 00 00 00 00 0D under mask FF 00 00 00 00 00 FF, got {}",
+                format_hex(&other.to_bytes().unwrap())
+            )
+        );
+        assert!(Expectation::mask("0E", "FF FF").is_err());
+    }
+}
@@ -0,0 +1,218 @@
+use std::{cell::RefCell, rc::Rc};
+
+use futures::{stream, Stream, StreamExt};
+
+use crate::ble::telegram::{Telegram, TelegramError, HEADER_LEN, MAX_FRAME_LEN, MIN_FRAME_LEN};
+
+/// Reassembles telegrams from arbitrary byte chunks, such as BLE notifications that are split by
+/// the MTU or that contain several telegrams at once. Frames are delimited by their length byte.
+#[derive(Debug, Default)]
+pub struct TelegramFramer {
+    buffer: Vec<u8>,
+    resyncing: bool,
+}
+
+impl TelegramFramer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.buffer.extend_from_slice(chunk);
+    }
+
+    /// Number of buffered bytes that are not yet part of a complete telegram.
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.resyncing = false;
+    }
+
+    /// Returns the next complete telegram, or `None` when more bytes are needed.
+    pub fn next_telegram(&mut self) -> Option<Result<Telegram, TelegramError>> {
+        self.next_frame()
+            .map(|frame| frame.and_then(|f| Telegram::from_bytes(&f)))
+    }
+
+    /// Returns the raw bytes of the next complete frame, or `None` when more bytes are needed.
+    ///
+    /// When a frame fails its checksum the framer drops a single byte and searches for the next
+    /// valid frame. Only the first error is reported until the stream is back in sync, bytes that
+    /// cannot start a frame at all are skipped silently.
+    pub fn next_frame(&mut self) -> Option<Result<Vec<u8>, TelegramError>> {
+        loop {
+            if self.buffer.len() < HEADER_LEN {
+                return None;
+            }
+
+            let frame_len = HEADER_LEN + self.buffer[HEADER_LEN - 1] as usize;
+            if !(MIN_FRAME_LEN..=MAX_FRAME_LEN).contains(&frame_len) {
+                // No telegram can start here.
+                self.buffer.remove(0);
+                continue;
+            }
+            if self.buffer.len() < frame_len {
+                // While resyncing the length byte may be garbage, so rather than waiting on it
+                // prefer a complete, valid telegram further along in the buffer.
+                match self.resyncing.then(|| self.find_valid_frame()).flatten() {
+                    Some(offset) => {
+                        self.buffer.drain(..offset);
+                        continue;
+                    }
+                    None => return None,
+                }
+            }
+
+            match Telegram::from_bytes(&self.buffer[..frame_len]) {
+                // An unknown command still means the frame itself is intact.
+                Ok(_) | Err(TelegramError::UnknownCommand(_)) => {
+                    self.resyncing = false;
+                    return Some(Ok(self.buffer.drain(..frame_len).collect()));
+                }
+                Err(e) => {
+                    self.buffer.remove(0);
+                    if !self.resyncing {
+                        self.resyncing = true;
+                        return Some(Err(e));
+                    }
+                }
+            }
+        }
+    }
+
+    fn find_valid_frame(&self) -> Option<usize> {
+        (1..self.buffer.len().saturating_sub(MIN_FRAME_LEN - 1)).find(|&offset| {
+            let frame = &self.buffer[offset..];
+            let frame_len = HEADER_LEN + frame[HEADER_LEN - 1] as usize;
+            frame_len >= MIN_FRAME_LEN
+                && frame.len() >= frame_len
+                && Telegram::from_bytes(&frame[..frame_len]).is_ok()
+        })
+    }
+}
+
+/// Keeps the notifications passing through [`tapped`] once enabled, so they can be recorded as
+/// received, including those that fail to frame.
+#[derive(Debug, Clone, Default)]
+pub struct NotificationTap(Rc<RefCell<Option<Vec<Vec<u8>>>>>);
+
+impl NotificationTap {
+    pub fn enable(&self) {
+        self.0.borrow_mut().get_or_insert_with(Vec::new);
+    }
+
+    /// The notifications since the last call, none while the tap isn't enabled.
+    pub fn take(&self) -> Vec<Vec<u8>> {
+        self.0
+            .borrow_mut()
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+}
+
+/// Passes `notifications` through, keeping a copy of each in `tap`.
+pub fn tapped<S>(notifications: S, tap: NotificationTap) -> impl Stream<Item = Vec<u8>>
+where
+    S: Stream<Item = Vec<u8>>,
+{
+    notifications.inspect(move |notification| {
+        if let Some(kept) = tap.0.borrow_mut().as_mut() {
+            kept.push(notification.clone());
+        }
+    })
+}
+
+/// Turns a stream of raw notifications into a stream of reassembled telegrams. The stream is
+/// fused, a transport can be read again after it closed.
+pub fn framed<S>(notifications: S) -> impl Stream<Item = Result<Telegram, TelegramError>>
+where
+    S: Stream<Item = Vec<u8>>,
+{
+    stream::unfold(
+        (Box::pin(notifications), TelegramFramer::new()),
+        |(mut notifications, mut framer)| async move {
+            loop {
+                if let Some(result) = framer.next_telegram() {
+                    return Some((result, (notifications, framer)));
+                }
+                framer.push(&notifications.next().await?);
+            }
+        },
+    )
+    .fuse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::telegram::Command;
+
+    const GREET: [u8; 11] = [
+        0x0E, 0x92, 0xFF, 0xFF, 0xFF, 0xFF, 0x04, 0x01, 0xCC, 0xB1, 0x21,
+    ];
+    const READ_REGISTER: [u8; 13] = [
+        0x0E, 0x92, 0x00, 0x7B, 0x9E, 0x98, 0x06, 0x01, 0xD2, 0x00, 0x31, 0x58, 0xEC,
+    ];
+
+    #[test]
+    fn test_split_frame() {
+        let mut framer = TelegramFramer::new();
+        framer.push(&READ_REGISTER[..4]);
+        assert_eq!(framer.next_telegram(), None);
+        framer.push(&READ_REGISTER[4..10]);
+        assert_eq!(framer.next_telegram(), None);
+        framer.push(&READ_REGISTER[10..]);
+
+        let telegram = framer.next_telegram().unwrap().unwrap();
+        assert_eq!(telegram.subcommand, 0xD2);
+        assert_eq!(telegram.data, vec![0x00, 0x31]);
+        assert_eq!(framer.pending(), 0);
+    }
+
+    #[test]
+    fn test_packed_frames() {
+        let mut framer = TelegramFramer::new();
+        framer.push(&[&GREET[..], &READ_REGISTER[..], &GREET[..3]].concat());
+
+        assert_eq!(framer.next_telegram().unwrap().unwrap().subcommand, 204);
+        assert_eq!(framer.next_telegram().unwrap().unwrap().subcommand, 0xD2);
+        assert_eq!(framer.next_telegram(), None);
+        assert_eq!(framer.pending(), 3);
+    }
+
+    #[test]
+    fn test_resync_after_bad_checksum() {
+        let mut corrupted = GREET;
+        corrupted[9] ^= 0xFF;
+
+        let mut framer = TelegramFramer::new();
+        framer.push(&[&corrupted[..], &READ_REGISTER[..]].concat());
+
+        assert!(matches!(
+            framer.next_telegram(),
+            Some(Err(TelegramError::BadChecksum { .. }))
+        ));
+        assert_eq!(
+            framer.next_telegram().unwrap().unwrap().data,
+            vec![0x00, 0x31]
+        );
+        assert_eq!(framer.next_telegram(), None);
+    }
+
+    #[test]
+    fn test_framed_stream() {
+        let chunks = vec![
+            READ_REGISTER[..5].to_vec(),
+            [&READ_REGISTER[5..], &GREET[..]].concat(),
+        ];
+        let telegrams: Vec<_> = futures::executor::block_on(framed(stream::iter(chunks)).collect());
+
+        assert_eq!(telegrams.len(), 2);
+        assert_eq!(telegrams[1].as_ref().unwrap().command, Command::Read);
+        assert_eq!(telegrams[1].as_ref().unwrap().subcommand, 204);
+    }
+}
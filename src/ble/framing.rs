@@ -0,0 +1,231 @@
+use std::{fmt::Display, io, str::FromStr};
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+
+use crate::ble::{
+    framer::TelegramFramer,
+    telegram::{format_hex, parse_hex, Telegram, TelegramError},
+};
+
+/// How telegrams are delimited on a byte stream, such as a pass-through TCP connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Every telegram is preceded by its length as a big endian `u16`.
+    #[default]
+    LengthPrefixed,
+    /// Telegrams as is, delimited by their length byte like on the wire.
+    Raw,
+    /// One telegram per line as hex bytes, e.g. `0E 92 FF FF FF FF 04 01 CC B1 21`.
+    Hex,
+    /// One telegram per line as a JSON object. Lines printed by `decode --output json` are read
+    /// as well, they carry the telegram in their `telegram` field.
+    Json,
+}
+
+impl Framing {
+    pub const ALL: [Framing; 4] = [Self::LengthPrefixed, Self::Raw, Self::Hex, Self::Json];
+
+    /// The bytes that carry `telegram` on the stream.
+    pub fn encode(&self, telegram: &Telegram) -> Result<Vec<u8>, TelegramError> {
+        match self {
+            Self::Json => {
+                let mut line = serde_json::to_vec(telegram).expect("telegrams serialize");
+                line.push(b'\n');
+                Ok(line)
+            }
+            _ => self.frame(&telegram.to_bytes()?),
+        }
+    }
+
+    /// The bytes that carry a frame on the stream. The frame may be damaged, except for JSON
+    /// which can only carry frames that decode.
+    pub fn frame(&self, bytes: &[u8]) -> Result<Vec<u8>, TelegramError> {
+        Ok(match self {
+            Self::LengthPrefixed => [&(bytes.len() as u16).to_be_bytes()[..], bytes].concat(),
+            Self::Raw => bytes.to_vec(),
+            Self::Hex => format!("{}\n", format_hex(bytes)).into_bytes(),
+            Self::Json => return self.encode(&Telegram::from_bytes(bytes)?),
+        })
+    }
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|f| f.to_string() == s)
+            .ok_or_else(|| {
+                format!(
+                    "unknown framing {}, expected one of length-prefixed, raw, hex, json",
+                    s
+                )
+            })
+    }
+}
+
+impl Display for Framing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::LengthPrefixed => "length-prefixed",
+            Self::Raw => "raw",
+            Self::Hex => "hex",
+            Self::Json => "json",
+        })
+    }
+}
+
+/// The framing of requests and of responses on a pass-through connection. Both default to
+/// [`Framing::LengthPrefixed`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamFraming {
+    pub request: Framing,
+    pub response: Framing,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum FrameError {
+    Telegram(TelegramError),
+    /// A line that isn't hex or JSON for a telegram.
+    Syntax(String),
+}
+
+impl From<TelegramError> for FrameError {
+    fn from(e: TelegramError) -> Self {
+        Self::Telegram(e)
+    }
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Telegram(e) => e.fmt(f),
+            Self::Syntax(e) => f.write_str(e),
+        }
+    }
+}
+
+/// Reads telegrams in one framing from a byte stream.
+pub struct FrameReader<R> {
+    reader: BufReader<R>,
+    framing: Framing,
+    framer: TelegramFramer,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(reader: R, framing: Framing) -> Self {
+        FrameReader {
+            reader: BufReader::new(reader),
+            framing,
+            framer: TelegramFramer::new(),
+        }
+    }
+
+    /// The next telegram, `None` at the end of the stream. A frame that can't be decoded yields
+    /// the reason, reading can continue after it.
+    pub async fn read(&mut self) -> io::Result<Option<Result<Telegram, FrameError>>> {
+        match self.framing {
+            Framing::LengthPrefixed => {
+                let mut len_buf = [0u8; 2];
+                match self.reader.read_exact(&mut len_buf).await {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                    Err(e) => return Err(e),
+                }
+                let mut buf = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+                self.reader.read_exact(&mut buf).await?;
+                Ok(Some(Telegram::from_bytes(&buf).map_err(FrameError::from)))
+            }
+            Framing::Raw => loop {
+                if let Some(result) = self.framer.next_telegram() {
+                    return Ok(Some(result.map_err(FrameError::from)));
+                }
+                let mut buf = [0u8; 512];
+                match self.reader.read(&mut buf).await? {
+                    0 => return Ok(None),
+                    n => self.framer.push(&buf[..n]),
+                }
+            },
+            Framing::Hex | Framing::Json => loop {
+                let mut line = String::new();
+                if self.reader.read_line(&mut line).await? == 0 {
+                    return Ok(None);
+                }
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                return Ok(Some(self.parse_line(line)));
+            },
+        }
+    }
+
+    fn parse_line(&self, line: &str) -> Result<Telegram, FrameError> {
+        if self.framing == Framing::Json {
+            let mut value: serde_json::Value =
+                serde_json::from_str(line).map_err(|e| FrameError::Syntax(e.to_string()))?;
+            if let Some(telegram) = value.get_mut("telegram") {
+                value = telegram.take();
+            }
+            return serde_json::from_value(value).map_err(|e| FrameError::Syntax(e.to_string()));
+        }
+        let bytes =
+            parse_hex(line).map_err(|t| FrameError::Syntax(format!("invalid hex byte {}", t)))?;
+        Ok(Telegram::from_bytes(&bytes)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::prefab;
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let greet = Telegram {
+            data: vec![1, 2],
+            ..prefab::greet()
+        };
+        assert_eq!(
+            Framing::Hex.encode(&greet).unwrap(),
+            b"0E 92 FF FF FF FF 06 01 CC 01 02 4D D9\n"
+        );
+
+        for framing in Framing::ALL {
+            assert_eq!(framing.to_string().parse(), Ok(framing));
+            let mut stream = framing.encode(&greet).unwrap();
+            stream.extend(framing.encode(&greet).unwrap());
+            let mut reader = FrameReader::new(stream.as_slice(), framing);
+            assert_eq!(reader.read().await.unwrap(), Some(Ok(greet.clone())));
+            assert_eq!(reader.read().await.unwrap(), Some(Ok(greet.clone())));
+            assert_eq!(reader.read().await.unwrap(), None, "{}", framing);
+        }
+
+        let mut reader = FrameReader::new(&b"\n0E 9G\n{}\n"[..], Framing::Hex);
+        assert_eq!(
+            reader.read().await.unwrap(),
+            Some(Err(FrameError::Syntax("invalid hex byte 9G".to_string())))
+        );
+        assert!(reader.read().await.unwrap().unwrap().is_err());
+        assert!("binary".parse::<Framing>().is_err());
+
+        // The output of `decode --output json`.
+        let report = serde_json::json!({
+            "line": 1,
+            "raw": "0E 92 FF FF FF FF 06 01 CC 01 02 4D D9",
+            "status": "ok",
+            "telegram": greet,
+        });
+        let stream = format!(
+            "{}\n{{\"status\":\"bad_checksum\",\"telegram\":null}}\n",
+            report
+        );
+        let mut reader = FrameReader::new(stream.as_bytes(), Framing::Json);
+        assert_eq!(reader.read().await.unwrap(), Some(Ok(greet.clone())));
+        assert!(matches!(
+            reader.read().await.unwrap(),
+            Some(Err(FrameError::Syntax(_)))
+        ));
+    }
+}
@@ -1,4 +1,22 @@
+pub mod client;
+pub mod connection;
+pub mod control_point;
+pub mod expect;
+pub mod framer;
+pub mod framing;
+pub mod modbus;
 pub mod prefab;
+pub mod pty;
+pub mod recorder;
+pub mod register_map;
+pub mod registry;
+pub mod replay;
+pub mod report;
+pub mod script;
+pub mod server;
+pub mod simulator;
+pub mod stats;
+pub mod supervisor;
 use dialoguer::{theme::ColorfulTheme, FuzzySelect};
 use futures::stream;
 use futures::{pin_mut, StreamExt};
@@ -8,7 +26,41 @@ use tokio::{
 };
 pub mod telegram;
 pub mod telegram_sequence;
-use bluer::{Adapter, Address, Device, DiscoveryFilter, DiscoveryTransport, Uuid};
+pub mod transport;
+use bluer::{Adapter, Address, Device, DiscoveryFilter, DiscoveryTransport, Session, Uuid};
+
+use std::time::Duration;
+use tokio::time::timeout;
+
+use crate::config::DeviceSelector;
+
+/// Opens the named adapter, or the default adapter when no name is given.
+pub async fn open_adapter(session: &Session, name: Option<&str>) -> bluer::Result<Adapter> {
+    match name {
+        Some(name) => session.adapter(name),
+        None => session.default_adapter().await,
+    }
+}
+
+/// Searches for the selected device for at most `scan_timeout`. Interactive selection is not
+/// limited in time.
+pub async fn find_selected_device(
+    adapter: &Adapter,
+    selector: &DeviceSelector,
+    scan_timeout: Duration,
+) -> bluer::Result<Option<Device>> {
+    match selector {
+        DeviceSelector::Name(name) => {
+            timeout(scan_timeout, find_device_name(adapter, name.clone()))
+                .await
+                .unwrap_or(Ok(None))
+        }
+        DeviceSelector::Address(addr) => timeout(scan_timeout, find_device(adapter, *addr))
+            .await
+            .unwrap_or(Ok(None)),
+        DeviceSelector::Interactive => find_device_select(adapter).await,
+    }
+}
 
 pub async fn find_device(adapter: &Adapter, addr: Address) -> bluer::Result<Option<Device>> {
     let filter = DiscoveryFilter {
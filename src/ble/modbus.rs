@@ -0,0 +1,672 @@
+use std::{fmt::Display, fs, io::ErrorKind, net::SocketAddr, path::Path, str::FromStr, sync::Arc};
+
+use anyhow::{anyhow, bail, Context, Result};
+use crc::{Crc, CRC_16_MODBUS};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::{sleep, Duration},
+};
+
+use crate::ble::{
+    register_map::READ_REGISTER,
+    server::{next_client_id, submit, Request},
+    telegram::{Command, Telegram, MAX_DATA_LEN},
+};
+
+pub const READ_HOLDING_REGISTERS: u8 = 0x03;
+pub const READ_INPUT_REGISTERS: u8 = 0x04;
+pub const WRITE_SINGLE_REGISTER: u8 = 0x06;
+pub const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+
+pub const ILLEGAL_FUNCTION: u8 = 0x01;
+pub const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+pub const ILLEGAL_DATA_VALUE: u8 = 0x03;
+pub const SERVER_DEVICE_FAILURE: u8 = 0x04;
+pub const GATEWAY_TARGET_FAILED: u8 = 0x0B;
+
+/// Length of the MBAP header, including the unit identifier.
+const MBAP_HEADER_LEN: usize = 7;
+const MAX_PDU_LEN: usize = 253;
+
+const CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_MODBUS);
+
+/// How Modbus requests and responses are framed on the TCP stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ModbusFraming {
+    /// Modbus TCP, every PDU behind an MBAP header.
+    #[default]
+    Tcp,
+    /// RTU frames over TCP: the unit identifier, the PDU and its CRC-16/MODBUS, little endian.
+    Rtu,
+}
+
+impl ModbusFraming {
+    pub const ALL: [ModbusFraming; 2] = [Self::Tcp, Self::Rtu];
+}
+
+impl FromStr for ModbusFraming {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|f| f.to_string() == s)
+            .ok_or_else(|| format!("unknown Modbus framing {}, expected tcp or rtu", s))
+    }
+}
+
+impl Display for ModbusFraming {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Tcp => "tcp",
+            Self::Rtu => "rtu",
+        })
+    }
+}
+
+/// Registers `start..=end`, read and written with one telegram per register. Read telegrams
+/// carry the register address, write telegrams the address and the value, both big endian.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RegisterBlock {
+    pub start: u16,
+    pub end: u16,
+    /// Subcommand of the `Read` telegram, the registers can't be read without.
+    #[serde(default)]
+    pub read: Option<u8>,
+    /// Subcommand of the `Write` telegram, the registers can't be written without.
+    #[serde(default)]
+    pub write: Option<u8>,
+}
+
+/// A function code whose request data is sent as the data of a telegram. The data of the
+/// response is returned as is.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FunctionMapping {
+    pub code: u8,
+    #[serde(deserialize_with = "command")]
+    pub command: Command,
+    pub subcommand: u8,
+}
+
+fn command<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Command, D::Error> {
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(de::Error::custom)
+}
+
+fn default_device_type() -> u16 {
+    3730
+}
+
+fn default_serial_number() -> u32 {
+    0xFFFFFFFF
+}
+
+/// How Modbus requests are translated to telegrams. Function codes in `functions` take
+/// precedence over the register functions.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ModbusMap {
+    #[serde(default = "default_device_type")]
+    pub device_type: u16,
+    #[serde(default = "default_serial_number")]
+    pub serial_number: u32,
+    #[serde(default, rename = "register")]
+    pub registers: Vec<RegisterBlock>,
+    #[serde(default, rename = "function")]
+    pub functions: Vec<FunctionMapping>,
+}
+
+impl Default for ModbusMap {
+    /// Every register can be read with [`READ_REGISTER`], none can be written.
+    fn default() -> Self {
+        ModbusMap {
+            device_type: default_device_type(),
+            serial_number: default_serial_number(),
+            registers: vec![RegisterBlock {
+                start: 0,
+                end: u16::MAX,
+                read: Some(READ_REGISTER),
+                write: None,
+            }],
+            functions: Vec::new(),
+        }
+    }
+}
+
+impl ModbusMap {
+    /// Loads a mapping from a TOML or JSON file, depending on its extension.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("could not read Modbus map {}", path.display()))?;
+        let map = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(&content)?,
+            Some("toml") => toml::from_str(&content)?,
+            _ => bail!(
+                "Modbus map {} must be a .toml or .json file",
+                path.display()
+            ),
+        };
+        Ok(map)
+    }
+
+    /// The mapping in `path`, or the default mapping.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        match path {
+            Some(path) => Self::from_file(path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// The block holding all `count` registers from `address`.
+    fn block(&self, address: u16, count: u16) -> Option<&RegisterBlock> {
+        let last = address as u32 + count as u32 - 1;
+        self.registers
+            .iter()
+            .find(|b| b.start <= address && last <= b.end as u32)
+    }
+
+    fn telegram(&self, command: Command, subcommand: u8, data: Vec<u8>) -> Telegram {
+        Telegram {
+            device_type: self.device_type,
+            serial_number: self.serial_number,
+            command,
+            subcommand,
+            data,
+        }
+    }
+
+    /// The telegrams to send for a request PDU, or the exception code to answer.
+    pub fn requests(&self, pdu: &[u8]) -> Result<Vec<Telegram>, u8> {
+        let (&function, body) = pdu.split_first().ok_or(ILLEGAL_FUNCTION)?;
+        if let Some(mapping) = self.functions.iter().find(|f| f.code == function) {
+            if body.len() > MAX_DATA_LEN {
+                return Err(ILLEGAL_DATA_VALUE);
+            }
+            return Ok(vec![self.telegram(
+                mapping.command,
+                mapping.subcommand,
+                body.to_vec(),
+            )]);
+        }
+
+        match function {
+            READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+                let (address, count) = (word(body, 0)?, word(body, 1)?);
+                if !(1..=125).contains(&count) {
+                    return Err(ILLEGAL_DATA_VALUE);
+                }
+                let subcommand = self
+                    .block(address, count)
+                    .and_then(|b| b.read)
+                    .ok_or(ILLEGAL_DATA_ADDRESS)?;
+                Ok((address..=address + (count - 1))
+                    .map(|a| self.telegram(Command::Read, subcommand, a.to_be_bytes().to_vec()))
+                    .collect())
+            }
+            WRITE_SINGLE_REGISTER => {
+                let (address, value) = (word(body, 0)?, word(body, 1)?);
+                let subcommand = self
+                    .block(address, 1)
+                    .and_then(|b| b.write)
+                    .ok_or(ILLEGAL_DATA_ADDRESS)?;
+                let data = [address.to_be_bytes(), value.to_be_bytes()].concat();
+                Ok(vec![self.telegram(Command::Write, subcommand, data)])
+            }
+            WRITE_MULTIPLE_REGISTERS => {
+                let (address, count) = (word(body, 0)?, word(body, 1)?);
+                let values = body.get(5..).ok_or(ILLEGAL_DATA_VALUE)?;
+                if !(1..=123).contains(&count)
+                    || body[4] as usize != values.len()
+                    || values.len() != count as usize * 2
+                {
+                    return Err(ILLEGAL_DATA_VALUE);
+                }
+                let subcommand = self
+                    .block(address, count)
+                    .and_then(|b| b.write)
+                    .ok_or(ILLEGAL_DATA_ADDRESS)?;
+                Ok((address..=address + (count - 1))
+                    .zip(values.chunks(2))
+                    .map(|(a, value)| {
+                        let data = [&a.to_be_bytes()[..], value].concat();
+                        self.telegram(Command::Write, subcommand, data)
+                    })
+                    .collect())
+            }
+            _ => Err(ILLEGAL_FUNCTION),
+        }
+    }
+
+    /// The response PDU for a request, given the responses to all its telegrams. Register
+    /// values are taken from the end of the response data, like
+    /// [`RegisterMap::decode_reply`](crate::ble::register_map::RegisterMap::decode_reply).
+    pub fn response(&self, pdu: &[u8], responses: &[Telegram]) -> Result<Vec<u8>, u8> {
+        let function = pdu[0];
+        if self.functions.iter().any(|f| f.code == function) {
+            let data = &responses[0].data;
+            if data.len() >= MAX_PDU_LEN {
+                return Err(SERVER_DEVICE_FAILURE);
+            }
+            return Ok([&[function], data.as_slice()].concat());
+        }
+
+        match function {
+            READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+                let mut response = vec![function, (responses.len() * 2) as u8];
+                for r in responses {
+                    let start = r.data.len().checked_sub(2).ok_or(SERVER_DEVICE_FAILURE)?;
+                    response.extend(&r.data[start..]);
+                }
+                Ok(response)
+            }
+            WRITE_SINGLE_REGISTER => Ok(pdu.to_vec()),
+            _ => Ok(pdu[..5].to_vec()),
+        }
+    }
+}
+
+/// The `index`th big endian word of a request body.
+fn word(body: &[u8], index: usize) -> Result<u16, u8> {
+    body.get(index * 2..index * 2 + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or(ILLEGAL_DATA_VALUE)
+}
+
+pub fn exception(function: u8, code: u8) -> Vec<u8> {
+    vec![function | 0x80, code]
+}
+
+/// Length of the RTU request at the start of `buffer`, `None` until enough of it arrived to
+/// tell. The register functions have a fixed layout, other functions end where the CRC first
+/// matches.
+fn rtu_request_len(buffer: &[u8], map: &ModbusMap) -> Option<usize> {
+    let function = *buffer.get(1)?;
+    if !map.functions.iter().any(|f| f.code == function) {
+        match function {
+            READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS | WRITE_SINGLE_REGISTER => {
+                return Some(8)
+            }
+            WRITE_MULTIPLE_REGISTERS => return buffer.get(6).map(|&n| 9 + n as usize),
+            _ => {}
+        }
+    }
+    (4..=buffer.len()).find(|&n| CRC.checksum(&buffer[..n - 2]).to_le_bytes() == buffer[n - 2..n])
+}
+
+/// Reads the next request, its header (the MBAP header or the RTU unit identifier) and PDU.
+/// `None` when the client closed the connection between requests.
+async fn read_request(
+    stream: &mut TcpStream,
+    framing: ModbusFraming,
+    map: &ModbusMap,
+    buffer: &mut Vec<u8>,
+) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    match framing {
+        ModbusFraming::Tcp => {
+            let mut header = vec![0u8; MBAP_HEADER_LEN];
+            match stream.read_exact(&mut header).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+            let protocol = u16::from_be_bytes([header[2], header[3]]);
+            let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+            if protocol != 0 || !(2..=MAX_PDU_LEN + 1).contains(&length) {
+                bail!("invalid MBAP header");
+            }
+            let mut pdu = vec![0u8; length - 1];
+            stream
+                .read_exact(&mut pdu)
+                .await
+                .context("failed to read request")?;
+            Ok(Some((header, pdu)))
+        }
+        ModbusFraming::Rtu => loop {
+            if let Some(len) = rtu_request_len(buffer, map).filter(|&len| len <= buffer.len()) {
+                let frame: Vec<u8> = buffer.drain(..len).collect();
+                let (body, crc) = frame.split_at(len - 2);
+                if CRC.checksum(body).to_le_bytes() != crc {
+                    bail!("invalid RTU frame CRC");
+                }
+                return Ok(Some((vec![body[0]], body[1..].to_vec())));
+            }
+            if buffer.len() >= MAX_PDU_LEN + 3 {
+                bail!("invalid RTU frame");
+            }
+            let mut chunk = [0u8; MAX_PDU_LEN + 3];
+            match stream.read(&mut chunk).await? {
+                0 if buffer.is_empty() => return Ok(None),
+                0 => return Err(anyhow!("failed to read request: incomplete RTU frame")),
+                n => buffer.extend(&chunk[..n]),
+            }
+        },
+    }
+}
+
+/// Frames a response PDU with the header of its request.
+fn frame_response(framing: ModbusFraming, header: &[u8], response: &[u8]) -> Vec<u8> {
+    match framing {
+        ModbusFraming::Tcp => {
+            let mut frame = header.to_vec();
+            frame[4..6].copy_from_slice(&(response.len() as u16 + 1).to_be_bytes());
+            frame.extend(response);
+            frame
+        }
+        ModbusFraming::Rtu => {
+            let mut frame = [header, response].concat();
+            frame.extend(CRC.checksum(&frame).to_le_bytes());
+            frame
+        }
+    }
+}
+
+/// Accepts Modbus clients and queues the telegrams of their requests on `requests`, next to
+/// those of the pass-through clients.
+pub async fn accept(
+    listener: TcpListener,
+    framing: ModbusFraming,
+    requests: mpsc::Sender<Request>,
+    map: Arc<ModbusMap>,
+) -> Result<()> {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tokio::spawn(serve_client(
+                    next_client_id(),
+                    stream,
+                    peer,
+                    framing,
+                    requests.clone(),
+                    map.clone(),
+                ));
+            }
+            Err(e) => {
+                eprintln!("Failed to accept Modbus client: {}", e);
+                sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+/// Answers requests until the client disconnects. The unit identifier is echoed and otherwise
+/// ignored, there is one device behind the gateway.
+async fn serve_client(
+    id: usize,
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    framing: ModbusFraming,
+    requests: mpsc::Sender<Request>,
+    map: Arc<ModbusMap>,
+) {
+    println!("[{}] Modbus client connected from {}", id, peer);
+    let mut buffer = Vec::new();
+    let reason = loop {
+        let (header, pdu) = match read_request(&mut stream, framing, &map, &mut buffer).await {
+            Ok(Some(request)) => request,
+            Ok(None) => break "closed by client".to_string(),
+            Err(e) => break format!("{:#}", e),
+        };
+
+        let response = match handle(id, &requests, &map, &pdu).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(code)) => {
+                println!(
+                    "[{}]    Modbus exception {:#04x} for function {:#04x}",
+                    id, code, pdu[0]
+                );
+                exception(pdu[0], code)
+            }
+            Err(e) => break e.to_string(),
+        };
+        let frame = frame_response(framing, &header, &response);
+        if let Err(e) = stream.write_all(&frame).await {
+            break e.to_string();
+        }
+    };
+    println!("[{}] Modbus client disconnected: {}", id, reason);
+}
+
+/// Sends the telegrams of a request and builds its response. The inner error is the exception
+/// code to answer, the outer one means the link closed.
+async fn handle(
+    id: usize,
+    requests: &mpsc::Sender<Request>,
+    map: &ModbusMap,
+    pdu: &[u8],
+) -> Result<Result<Vec<u8>, u8>> {
+    let telegrams = match map.requests(pdu) {
+        Ok(t) => t,
+        Err(code) => return Ok(Err(code)),
+    };
+    let mut responses = Vec::with_capacity(telegrams.len());
+    for telegram in telegrams {
+        match submit(requests, id, telegram).await? {
+            Some(response) => responses.push(response),
+            None => return Ok(Err(GATEWAY_TARGET_FAILED)),
+        }
+    }
+    Ok(map.response(pdu, &responses))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::{
+        client::Client,
+        registry::Registry,
+        server::{serve_link, Clients, QUEUE_DEPTH},
+        simulator::{Simulator, SimulatorConfig},
+    };
+    use crate::protocol::GREET;
+
+    fn map() -> ModbusMap {
+        toml::from_str(
+            r#"
+            [[register]]
+            start = 0x0000
+            end = 0x00FF
+            read = 0xD2
+
+            [[register]]
+            start = 0x1000
+            end = 0x1001
+            read = 0xD2
+            write = 0xD3
+
+            [[function]]
+            code = 65
+            command = "read"
+            subcommand = 204
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_requests() {
+        let map = map();
+        let telegrams = map.requests(&[0x03, 0x00, 0x31, 0x00, 0x02]).unwrap();
+        assert_eq!(telegrams.len(), 2);
+        assert_eq!(telegrams[1].subcommand, READ_REGISTER);
+        assert_eq!(telegrams[1].data, vec![0x00, 0x32]);
+
+        let write = [0x10, 0x10, 0x00, 0x00, 0x02, 0x04, 0x00, 0x0A, 0x01, 0x02];
+        let telegrams = map.requests(&write).unwrap();
+        assert_eq!(telegrams[1].command, Command::Write);
+        assert_eq!(telegrams[1].data, vec![0x10, 0x01, 0x01, 0x02]);
+        assert_eq!(map.response(&write, &telegrams).unwrap(), write[..5]);
+
+        let custom = map.requests(&[65, 1, 2]).unwrap();
+        assert_eq!(
+            (custom[0].subcommand, custom[0].data.clone()),
+            (GREET, vec![1, 2])
+        );
+
+        assert_eq!(
+            map.requests(&[0x03, 0x00, 0xFF, 0x00, 0x02]),
+            Err(ILLEGAL_DATA_ADDRESS)
+        );
+        assert_eq!(
+            map.requests(&[0x06, 0x00, 0x31, 0x00, 0x01]),
+            Err(ILLEGAL_DATA_ADDRESS)
+        );
+        assert_eq!(
+            map.requests(&[0x03, 0x00, 0x31, 0x00, 0x00]),
+            Err(ILLEGAL_DATA_VALUE)
+        );
+        assert_eq!(map.requests(&[0x03, 0x00]), Err(ILLEGAL_DATA_VALUE));
+        assert_eq!(
+            map.requests(&[0x01, 0x00, 0x00, 0x00, 0x01]),
+            Err(ILLEGAL_FUNCTION)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gateway() {
+        let config = SimulatorConfig {
+            registers: [(0x0031, 215), (0x0032, 0xFFFF)].into(),
+            ..Default::default()
+        };
+        let mut client = Client::new(Simulator::new(config).transport(), Default::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel(QUEUE_DEPTH);
+        let registry = Registry::builtin();
+        let connected = Clients::default();
+
+        let server = async {
+            tokio::select! {
+                r = accept(listener, ModbusFraming::Tcp, tx, Arc::new(ModbusMap::default())) => r,
+                r = serve_link(
+                    &mut client,
+                    rx,
+                    &registry,
+                    Duration::from_millis(100),
+                    &connected,
+                ) => r,
+            }
+        };
+        let poll = async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let mut responses = Vec::new();
+            for request in [
+                [
+                    0x00, 0x07, 0x00, 0x00, 0x00, 0x06, 0x01, 0x03, 0x00, 0x31, 0x00, 0x02,
+                ],
+                [
+                    0x00, 0x08, 0x00, 0x00, 0x00, 0x06, 0x01, 0x06, 0x00, 0x31, 0x00, 0x01,
+                ],
+            ] {
+                stream.write_all(&request).await.unwrap();
+                let mut response = vec![0u8; 64];
+                let n = stream.read(&mut response).await.unwrap();
+                response.truncate(n);
+                responses.push(response);
+            }
+            responses
+        };
+        let responses = tokio::select! {
+            r = server => panic!("server stopped: {:?}", r.err()),
+            r = poll => r,
+        };
+        assert_eq!(
+            responses[0],
+            vec![0x00, 0x07, 0x00, 0x00, 0x00, 0x07, 0x01, 0x03, 0x04, 0x00, 0xD7, 0xFF, 0xFF]
+        );
+        assert_eq!(
+            responses[1],
+            vec![
+                0x00,
+                0x08,
+                0x00,
+                0x00,
+                0x00,
+                0x03,
+                0x01,
+                0x86,
+                ILLEGAL_DATA_ADDRESS
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rtu_request_len() {
+        let map = map();
+        let read = [0x01, 0x03, 0x00, 0x31, 0x00, 0x02, 0x95, 0xC4];
+        assert_eq!(rtu_request_len(&read[..1], &map), None);
+        assert_eq!(rtu_request_len(&read[..2], &map), Some(8));
+        let write = [0x01, 0x10, 0x10, 0x00, 0x00, 0x01, 0x02];
+        assert_eq!(rtu_request_len(&write[..6], &map), None);
+        assert_eq!(rtu_request_len(&write, &map), Some(11));
+
+        // A mapped function ends at the first matching CRC.
+        let custom = frame_response(ModbusFraming::Rtu, &[0x01], &[65, 1, 2]);
+        assert_eq!(rtu_request_len(&custom[..5], &map), None);
+        assert_eq!(rtu_request_len(&custom, &map), Some(6));
+    }
+
+    #[tokio::test]
+    async fn test_rtu_gateway() {
+        let config = SimulatorConfig {
+            registers: [(0x0031, 215)].into(),
+            ..Default::default()
+        };
+        let mut client = Client::new(Simulator::new(config).transport(), Default::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel(QUEUE_DEPTH);
+        let registry = Registry::builtin();
+        let connected = Clients::default();
+
+        let server = async {
+            tokio::select! {
+                r = accept(listener, ModbusFraming::Rtu, tx, Arc::new(ModbusMap::default())) => r,
+                r = serve_link(
+                    &mut client,
+                    rx,
+                    &registry,
+                    Duration::from_millis(100),
+                    &connected,
+                ) => r,
+            }
+        };
+        let poll = async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let mut responses = Vec::new();
+            let read = frame_response(ModbusFraming::Rtu, &[0x11], &[0x03, 0x00, 0x31, 0x00, 0x01]);
+            let write =
+                frame_response(ModbusFraming::Rtu, &[0x11], &[0x06, 0x00, 0x31, 0x00, 0x01]);
+            // The read arrives in two segments, the write right behind it.
+            stream.write_all(&read[..3]).await.unwrap();
+            sleep(Duration::from_millis(10)).await;
+            stream
+                .write_all(&[&read[3..], write.as_slice()].concat())
+                .await
+                .unwrap();
+            for len in [7, 5] {
+                let mut response = vec![0u8; len];
+                stream.read_exact(&mut response).await.unwrap();
+                responses.push(response);
+            }
+            responses
+        };
+        let responses = tokio::select! {
+            r = server => panic!("server stopped: {:?}", r.err()),
+            r = poll => r,
+        };
+        assert_eq!(
+            responses[0],
+            frame_response(ModbusFraming::Rtu, &[0x11], &[0x03, 0x02, 0x00, 0xD7])
+        );
+        assert_eq!(
+            responses[1],
+            frame_response(ModbusFraming::Rtu, &[0x11], &[0x86, ILLEGAL_DATA_ADDRESS])
+        );
+    }
+}
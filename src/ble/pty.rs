@@ -0,0 +1,299 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::{fd::AsRawFd, unix::fs::symlink},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use colored::Colorize;
+use nix::{
+    fcntl::{fcntl, FcntlArg, OFlag},
+    ioctl_read_bad, libc,
+    pty::{grantpt, posix_openpt, ptsname_r, unlockpt, PtyMaster},
+    sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg},
+};
+use tokio::io::unix::AsyncFd;
+
+use crate::ble::{
+    client::Client,
+    framer::TelegramFramer,
+    registry::Registry,
+    server::{forward, Routing},
+    transport::TelegramTransport,
+};
+use tokio::time::Duration;
+
+ioctl_read_bad!(input_queue_len, libc::FIONREAD, libc::c_int);
+
+/// A pseudo-terminal that behaves like the serial port of a wired module. The terminal is raw,
+/// so bytes pass through unchanged, and reachable through an optional symlink that is removed
+/// again on drop.
+pub struct Pty {
+    master: AsyncFd<PtyMaster>,
+    /// Kept open so the master doesn't fail with `EIO` while no program has the terminal open,
+    /// and to see how much of the written data is still unread.
+    slave: File,
+    path: PathBuf,
+    link: Option<PathBuf>,
+}
+
+impl Pty {
+    pub fn open(link: Option<&Path>) -> Result<Self> {
+        let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY)?;
+        grantpt(&master)?;
+        unlockpt(&master)?;
+        let path = PathBuf::from(ptsname_r(&master)?);
+
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("could not open {}", path.display()))?;
+        let mut termios = tcgetattr(&slave)?;
+        cfmakeraw(&mut termios);
+        tcsetattr(&slave, SetArg::TCSANOW, &termios)?;
+
+        let flags = OFlag::from_bits_truncate(fcntl(master.as_raw_fd(), FcntlArg::F_GETFL)?);
+        fcntl(
+            master.as_raw_fd(),
+            FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK),
+        )?;
+
+        if let Some(link) = link {
+            // Replace the link of an earlier session, but never a regular file.
+            if fs::symlink_metadata(link).is_ok_and(|m| m.file_type().is_symlink()) {
+                fs::remove_file(link)?;
+            }
+            symlink(&path, link)
+                .with_context(|| format!("could not create link {}", link.display()))?;
+        }
+
+        Ok(Pty {
+            master: AsyncFd::new(master)?,
+            slave,
+            path,
+            link: link.map(Path::to_path_buf),
+        })
+    }
+
+    /// The terminal device, e.g. `/dev/pts/3`.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn link(&self) -> Option<&Path> {
+        self.link.as_deref()
+    }
+
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.master.readable().await?;
+            if let Ok(result) = guard.try_io(|master| master.get_ref().read(buf)) {
+                return result;
+            }
+        }
+    }
+
+    pub async fn write_all(&self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let mut guard = self.master.writable().await?;
+            if let Ok(result) = guard.try_io(|master| master.get_ref().write(buf)) {
+                buf = &buf[result?..];
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` without waiting, unless the terminal still holds data that no program has
+    /// read, e.g. while no tool has it open. `false` when `buf` was dropped, so an absent tool
+    /// can't block the bridge and doesn't find a backlog once it attaches.
+    pub fn offer(&self, buf: &[u8]) -> io::Result<bool> {
+        let mut unread: libc::c_int = 0;
+        // SAFETY: FIONREAD writes the number of queued bytes to `unread`.
+        unsafe { input_queue_len(self.slave.as_raw_fd(), &mut unread) }?;
+        if unread > 0 {
+            return Ok(false);
+        }
+        match self.master.get_ref().write(buf) {
+            Ok(n) => Ok(n == buf.len()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Drop for Pty {
+    fn drop(&mut self) {
+        if let Some(link) = &self.link {
+            let _ = fs::remove_file(link);
+        }
+    }
+}
+
+/// Forwards the telegrams written to the terminal to the device and writes the responses back,
+/// until the link closes. Telegrams are delimited by their length byte, like on the wire.
+/// Notifications that answer no request are written to the terminal too, unless `routing` is
+/// [`Routing::None`], and dropped while the terminal isn't read, see [`Pty::offer`].
+pub async fn bridge<T: TelegramTransport>(
+    pty: &Pty,
+    client: &mut Client<T>,
+    registry: &Registry,
+    control_timeout: Duration,
+    routing: Routing,
+) -> Result<()> {
+    let notify = routing != Routing::None;
+    let mut framer = TelegramFramer::new();
+    let mut buf = [0u8; 512];
+    loop {
+        let n = tokio::select! {
+            n = pty.read(&mut buf) => n?,
+            notification = client.notification() => {
+                match notification {
+                    Some(Ok(telegram)) => {
+                        println!(
+                            "[pty] {}: {}",
+                            "Notification".yellow(),
+                            registry.display(&telegram)
+                        );
+                        if notify {
+                            pty.offer(&telegram.to_bytes()?)?;
+                        }
+                    }
+                    Some(Err(e)) => println!("[pty]    Error in notification {}", e),
+                    None => bail!("link to the device closed"),
+                }
+                continue;
+            }
+        };
+        framer.push(&buf[..n]);
+        while let Some(telegram) = framer.next_telegram() {
+            let telegram = match telegram {
+                Ok(t) => t,
+                Err(e) => {
+                    println!("[pty]    Error in request {}", e);
+                    continue;
+                }
+            };
+            let response = forward(client, "[pty]", &telegram, registry, control_timeout).await?;
+            for stray in client.take_strays() {
+                if notify {
+                    pty.offer(&stray.to_bytes()?)?;
+                }
+            }
+            if let Some(response) = response {
+                pty.write_all(&response.to_bytes()?).await?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::{
+        framer::TelegramFramer,
+        prefab::greet,
+        simulator::{Simulator, SimulatorConfig},
+        telegram::Telegram,
+        transport::LoopbackTransport,
+    };
+    use crate::protocol::{GREET, TESTBENCH};
+
+    #[tokio::test]
+    async fn test_bridge() {
+        let link = std::env::temp_dir().join(format!("ble-pty-{}", std::process::id()));
+        let pty = Pty::open(Some(&link)).unwrap();
+        assert_eq!(fs::read_link(&link).unwrap(), pty.path());
+
+        let mut client = Client::new(
+            Simulator::new(SimulatorConfig::default()).transport(),
+            Default::default(),
+        );
+        let registry = Registry::builtin();
+        let greet = greet();
+
+        // A legacy tool writing a telegram in two chunks.
+        let bytes = greet.to_bytes().unwrap();
+        let mut tool = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&link)
+            .unwrap();
+        tool.write_all(&bytes[..5]).unwrap();
+        tool.write_all(&bytes[5..]).unwrap();
+        let read = tokio::task::spawn_blocking(move || {
+            let mut response = vec![0u8; 20];
+            tool.read_exact(&mut response).unwrap();
+            response
+        });
+
+        let response = tokio::select! {
+            r = bridge(&pty, &mut client, &registry, Duration::from_millis(100), Routing::All) => {
+                panic!("bridge stopped: {:?}", r)
+            }
+            r = read => r.unwrap(),
+        };
+        assert_eq!(Telegram::from_bytes(&response).unwrap().data, b"BlueSmile");
+
+        drop(pty);
+        assert!(fs::symlink_metadata(&link).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_notifications_without_reader() {
+        let pty = Pty::open(None).unwrap();
+        let (transport, mut device) = LoopbackTransport::pair();
+        let mut client = Client::new(transport, Default::default());
+        let registry = Registry::builtin();
+        let event = Telegram {
+            subcommand: TESTBENCH,
+            data: vec![0; 100],
+            ..greet()
+        };
+        // Far more than the terminal buffers while no tool has it open.
+        for _ in 0..1000 {
+            device.send(&event).unwrap();
+        }
+        let answer = async {
+            while let Some(mut request) = device.recv().await {
+                request.data = b"BlueSmile".to_vec();
+                device.send(&request).unwrap();
+            }
+        };
+
+        let path = pty.path().to_path_buf();
+        let tool = tokio::task::spawn_blocking(move || {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            let mut tool = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .unwrap();
+            let greet = greet();
+            tool.write_all(&greet.to_bytes().unwrap()).unwrap();
+            let mut framer = TelegramFramer::new();
+            let mut received = Vec::new();
+            let mut buf = [0u8; 256];
+            while received.last().map(|t: &Telegram| t.subcommand) != Some(GREET) {
+                let n = tool.read(&mut buf).unwrap();
+                framer.push(&buf[..n]);
+                while let Some(telegram) = framer.next_telegram() {
+                    received.push(telegram.unwrap());
+                }
+            }
+            received
+        });
+
+        let received = tokio::select! {
+            r = bridge(&pty, &mut client, &registry, Duration::from_millis(100), Routing::All) => {
+                panic!("bridge stopped: {:?}", r)
+            }
+            _ = answer => panic!("device stopped"),
+            r = tokio::time::timeout(Duration::from_secs(5), tool) => r.unwrap().unwrap(),
+        };
+        // The event written before the terminal stopped being read is all that's left.
+        assert!(received.len() <= 2, "{} telegrams", received.len());
+        assert_eq!(received.last().unwrap().data, b"BlueSmile");
+    }
+}
@@ -0,0 +1,406 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use bluer::Uuid;
+use serde::{Deserialize, Serialize};
+
+use crate::ble::{
+    telegram::{format_hex, Telegram, TelegramError},
+    transport::TelegramTransport,
+};
+
+/// Link type of the pcapng captures, `LINKTYPE_USER0`. Every packet starts with a byte for the
+/// direction (0 sent, 1 received) and the 16 bytes of the characteristic UUID, followed by the
+/// frame.
+pub const PCAPNG_LINKTYPE: u16 = 147;
+
+/// Decode status of frames that aren't telegrams, such as control point commands.
+pub const STATUS_RAW: &str = "raw";
+pub const STATUS_OK: &str = "ok";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Tx,
+    Rx,
+}
+
+/// A line of a JSONL recording.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// Microseconds since the Unix epoch.
+    pub timestamp_us: u64,
+    pub direction: Direction,
+    pub characteristic: String,
+    /// The frame in hex. Received frames are the testbench notifications as they arrived, so a
+    /// telegram may span several records. Absent when a frame failed to decode on a transport
+    /// without notifications, whose bytes were lost.
+    pub data: Option<String>,
+    /// `ok`, `raw`, or why the frame failed to decode.
+    pub status: String,
+}
+
+enum Sink {
+    Jsonl(BufWriter<File>),
+    Pcapng(BufWriter<File>),
+}
+
+/// Writes the frames sent and received to a file, as JSONL or as pcapng when the file has a
+/// `.pcapng` extension. Every record is flushed right away, so an interrupted session still
+/// leaves a usable trace.
+pub struct Recorder {
+    sink: Sink,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("could not create recording {}", path.display()))?;
+        let mut file = BufWriter::new(file);
+        let sink = match path.extension().and_then(|e| e.to_str()) {
+            Some("pcapng") => {
+                write_pcapng_header(&mut file)?;
+                Sink::Pcapng(file)
+            }
+            _ => Sink::Jsonl(file),
+        };
+        Ok(Recorder { sink })
+    }
+
+    /// Records a frame or notification of the testbench characteristic, with its decode status.
+    pub fn telegram(&mut self, direction: Direction, char: Uuid, bytes: &[u8]) -> io::Result<()> {
+        let status = match Telegram::from_bytes(bytes) {
+            Ok(_) => STATUS_OK.to_string(),
+            Err(e) => e.to_string(),
+        };
+        self.record(direction, char, Some(bytes), status)
+    }
+
+    /// Records a received frame that failed to decode.
+    pub fn error(&mut self, char: Uuid, error: TelegramError) -> io::Result<()> {
+        self.record(Direction::Rx, char, None, error.to_string())
+    }
+
+    /// Records bytes that aren't a telegram.
+    pub fn raw(&mut self, direction: Direction, char: Uuid, bytes: &[u8]) -> io::Result<()> {
+        self.record(direction, char, Some(bytes), STATUS_RAW.to_string())
+    }
+
+    fn record(
+        &mut self,
+        direction: Direction,
+        char: Uuid,
+        bytes: Option<&[u8]>,
+        status: String,
+    ) -> io::Result<()> {
+        let timestamp_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64);
+        match &mut self.sink {
+            Sink::Jsonl(file) => {
+                let record = Record {
+                    timestamp_us,
+                    direction,
+                    characteristic: char.to_string(),
+                    data: bytes.map(format_hex),
+                    status,
+                };
+                serde_json::to_writer(&mut *file, &record)?;
+                writeln!(file)?;
+                file.flush()
+            }
+            Sink::Pcapng(file) => {
+                let comment = (status != STATUS_OK && status != STATUS_RAW).then_some(status);
+                write_pcapng_packet(
+                    file,
+                    timestamp_us,
+                    direction,
+                    char,
+                    bytes.unwrap_or_default(),
+                    comment.as_deref(),
+                )?;
+                file.flush()
+            }
+        }
+    }
+}
+
+fn write_pcapng_header(file: &mut impl Write) -> io::Result<()> {
+    // Section header block, the section length is left unspecified.
+    file.write_all(&0x0A0D0D0Au32.to_le_bytes())?;
+    file.write_all(&28u32.to_le_bytes())?;
+    file.write_all(&0x1A2B3C4Du32.to_le_bytes())?;
+    file.write_all(&1u16.to_le_bytes())?;
+    file.write_all(&0u16.to_le_bytes())?;
+    file.write_all(&(-1i64).to_le_bytes())?;
+    file.write_all(&28u32.to_le_bytes())?;
+
+    // Interface description block with microsecond timestamps and no snap length.
+    file.write_all(&1u32.to_le_bytes())?;
+    file.write_all(&20u32.to_le_bytes())?;
+    file.write_all(&PCAPNG_LINKTYPE.to_le_bytes())?;
+    file.write_all(&0u16.to_le_bytes())?;
+    file.write_all(&0u32.to_le_bytes())?;
+    file.write_all(&20u32.to_le_bytes())
+}
+
+fn write_pcapng_packet(
+    file: &mut impl Write,
+    timestamp_us: u64,
+    direction: Direction,
+    char: Uuid,
+    frame: &[u8],
+    comment: Option<&str>,
+) -> io::Result<()> {
+    let mut packet = vec![match direction {
+        Direction::Tx => 0,
+        Direction::Rx => 1,
+    }];
+    packet.extend_from_slice(char.as_bytes());
+    packet.extend_from_slice(frame);
+
+    let mut options = Vec::new();
+    // epb_flags, bits 0-1 hold the direction: 1 inbound, 2 outbound.
+    let flags: u32 = match direction {
+        Direction::Tx => 2,
+        Direction::Rx => 1,
+    };
+    push_option(&mut options, 2, &flags.to_le_bytes());
+    if let Some(comment) = comment {
+        push_option(&mut options, 1, comment.as_bytes());
+    }
+    push_option(&mut options, 0, &[]);
+
+    let total_len = (32 + padded(packet.len()) + options.len()) as u32;
+    file.write_all(&6u32.to_le_bytes())?;
+    file.write_all(&total_len.to_le_bytes())?;
+    file.write_all(&0u32.to_le_bytes())?;
+    file.write_all(&((timestamp_us >> 32) as u32).to_le_bytes())?;
+    file.write_all(&(timestamp_us as u32).to_le_bytes())?;
+    file.write_all(&(packet.len() as u32).to_le_bytes())?;
+    file.write_all(&(packet.len() as u32).to_le_bytes())?;
+    packet.resize(padded(packet.len()), 0);
+    file.write_all(&packet)?;
+    file.write_all(&options)?;
+    file.write_all(&total_len.to_le_bytes())
+}
+
+fn push_option(options: &mut Vec<u8>, code: u16, value: &[u8]) {
+    options.extend_from_slice(&code.to_le_bytes());
+    options.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    options.extend_from_slice(value);
+    options.resize(options.len() + padded(value.len()) - value.len(), 0);
+}
+
+fn padded(len: usize) -> usize {
+    len.div_ceil(4) * 4
+}
+
+/// Records the traffic of another transport. Without a recorder it passes everything through.
+/// Received notifications are recorded verbatim when the transport keeps them, otherwise the
+/// decoded telegrams are.
+pub struct RecordingTransport<T> {
+    inner: T,
+    recorder: Option<Recorder>,
+    testbench: Uuid,
+    control_point: Uuid,
+    notifications: bool,
+}
+
+impl<T: TelegramTransport> RecordingTransport<T> {
+    pub fn new(
+        mut inner: T,
+        recorder: Option<Recorder>,
+        testbench: Uuid,
+        control_point: Uuid,
+    ) -> Self {
+        let notifications = recorder.is_some() && inner.keep_notifications();
+        RecordingTransport {
+            inner,
+            recorder,
+            testbench,
+            control_point,
+            notifications,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Records with `f`, a failing recording is reported but doesn't stop the session.
+    fn record(&mut self, f: impl FnOnce(&mut Recorder) -> io::Result<()>) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = f(recorder) {
+                eprintln!("Recording failed: {}", e);
+                self.recorder = None;
+            }
+        }
+    }
+}
+
+impl<T: TelegramTransport> TelegramTransport for RecordingTransport<T> {
+    async fn send(&mut self, telegram: &Telegram) -> Result<()> {
+        self.inner.send(telegram).await?;
+        let char = self.testbench;
+        let bytes = telegram.to_bytes()?;
+        self.record(|r| r.telegram(Direction::Tx, char, &bytes));
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Option<Result<Telegram, TelegramError>> {
+        let received = self.inner.recv().await?;
+        let char = self.testbench;
+        if self.notifications {
+            for notification in self.inner.take_notifications() {
+                self.record(|r| r.telegram(Direction::Rx, char, &notification));
+            }
+            return Some(received);
+        }
+        match &received {
+            Ok(telegram) => {
+                let bytes = telegram.to_bytes().unwrap_or_default();
+                self.record(|r| r.telegram(Direction::Rx, char, &bytes));
+            }
+            Err(error) => self.record(|r| r.error(char, *error)),
+        }
+        Some(received)
+    }
+
+    async fn send_control(&mut self, command: &[u8]) -> Result<()> {
+        self.inner.send_control(command).await?;
+        let char = self.control_point;
+        self.record(|r| r.raw(Direction::Tx, char, command));
+        Ok(())
+    }
+
+    async fn recv_control(&mut self) -> Option<Vec<u8>> {
+        let received = self.inner.recv_control().await?;
+        let char = self.control_point;
+        self.record(|r| r.raw(Direction::Rx, char, &received));
+        Some(received)
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.inner.close().await
+    }
+}
+
+/// Reads the records of a JSONL recording.
+pub fn read_records(path: &Path) -> Result<Vec<Record>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read recording {}", path.display()))?;
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .map_err(|e| anyhow!("{}:{}: invalid record: {}", path.display(), i + 1, e))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::{
+        prefab::greet,
+        simulator::{Simulator, SimulatorConfig},
+        transport::LoopbackTransport,
+    };
+
+    #[tokio::test]
+    async fn test_record_jsonl() {
+        let path = std::env::temp_dir().join(format!("ble-record-{}.jsonl", std::process::id()));
+        let testbench = Uuid::from_u128(1);
+        let control_point = Uuid::from_u128(2);
+
+        let recorder = Recorder::create(&path).unwrap();
+        let simulator = Simulator::new(SimulatorConfig::default()).transport();
+        let mut transport =
+            RecordingTransport::new(simulator, Some(recorder), testbench, control_point);
+        transport.send(&greet()).await.unwrap();
+        transport.recv().await.unwrap().unwrap();
+        transport.send_control(&[0x01, 0, 0, 0, 0]).await.unwrap();
+        drop(transport);
+
+        let records = read_records(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].direction, Direction::Tx);
+        assert_eq!(
+            records[0].data.as_deref(),
+            Some("0E 92 FF FF FF FF 04 01 CC B1 21")
+        );
+        assert_eq!(records[1].direction, Direction::Rx);
+        assert_eq!(records[1].status, STATUS_OK);
+        assert_eq!(records[1].characteristic, testbench.to_string());
+        assert_eq!(records[2].characteristic, control_point.to_string());
+        assert_eq!(records[2].status, STATUS_RAW);
+        assert!(records[0].timestamp_us <= records[1].timestamp_us);
+    }
+
+    #[tokio::test]
+    async fn test_record_notifications() {
+        let path = std::env::temp_dir().join(format!("ble-notify-{}.jsonl", std::process::id()));
+        let (loopback, device) = LoopbackTransport::pair();
+        let recorder = Recorder::create(&path).unwrap();
+        let mut transport =
+            RecordingTransport::new(loopback, Some(recorder), Uuid::nil(), Uuid::nil());
+
+        let mut corrupt = greet().to_bytes().unwrap();
+        corrupt[10] ^= 0xFF;
+        device.send_raw(corrupt).unwrap();
+        assert!(transport.recv().await.unwrap().is_err());
+        let greet_bytes = greet().to_bytes().unwrap();
+        device.send_raw(greet_bytes[..4].to_vec()).unwrap();
+        device.send_raw(greet_bytes[4..].to_vec()).unwrap();
+        assert_eq!(transport.recv().await, Some(Ok(greet())));
+        drop(transport);
+
+        let records = read_records(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let data: Vec<_> = records.iter().map(|r| r.data.as_deref()).collect();
+        assert_eq!(
+            data,
+            [
+                Some("0E 92 FF FF FF FF 04 01 CC B1 DE"),
+                Some("0E 92 FF FF"),
+                Some("FF FF 04 01 CC B1 21"),
+            ]
+        );
+        assert!(records[0].status.starts_with("invalid checksum"));
+    }
+
+    #[test]
+    fn test_pcapng_blocks() {
+        let mut file = Vec::new();
+        write_pcapng_header(&mut file).unwrap();
+        assert_eq!(file.len(), 48);
+
+        let mut packet = Vec::new();
+        let frame = greet().to_bytes().unwrap();
+        write_pcapng_packet(
+            &mut packet,
+            1,
+            Direction::Rx,
+            Uuid::nil(),
+            &frame,
+            Some("bad"),
+        )
+        .unwrap();
+        // 17 header bytes and 11 frame bytes, padded to 28, flags and comment options.
+        let total_len = 32 + 28 + 8 + 8 + 4;
+        assert_eq!(packet.len(), total_len);
+        assert_eq!(packet[4..8], (total_len as u32).to_le_bytes());
+        assert_eq!(packet[total_len - 4..], (total_len as u32).to_le_bytes());
+        assert_eq!(packet[20..24], 28u32.to_le_bytes());
+        assert_eq!(packet[28], 1);
+    }
+}
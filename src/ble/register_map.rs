@@ -0,0 +1,333 @@
+use std::{fmt::Display, fs, path::Path};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::args::parse_number;
+use crate::ble::telegram::{Command, Telegram};
+
+/// Subcommand of a `Read` telegram carrying a two byte register address.
+pub const READ_REGISTER: u8 = 0xD2;
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum RegisterType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+}
+
+impl RegisterType {
+    pub fn size(&self) -> usize {
+        match self {
+            Self::U8 | Self::I8 => 1,
+            Self::U16 | Self::I16 => 2,
+            Self::U32 | Self::I32 => 4,
+        }
+    }
+
+    /// Interprets big endian bytes as a value of this type.
+    pub fn decode(&self, bytes: &[u8]) -> Option<i64> {
+        let b = bytes.get(..self.size())?;
+        Some(match self {
+            Self::U8 => b[0] as i64,
+            Self::I8 => b[0] as i8 as i64,
+            Self::U16 => u16::from_be_bytes([b[0], b[1]]) as i64,
+            Self::I16 => i16::from_be_bytes([b[0], b[1]]) as i64,
+            Self::U32 => u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as i64,
+            Self::I32 => i32::from_be_bytes([b[0], b[1], b[2], b[3]]) as i64,
+        })
+    }
+
+    /// Big endian bytes for `raw`, `None` when it doesn't fit this type.
+    pub fn encode(&self, raw: i64) -> Option<Vec<u8>> {
+        Some(match self {
+            Self::U8 => vec![u8::try_from(raw).ok()?],
+            Self::I8 => i8::try_from(raw).ok()?.to_be_bytes().to_vec(),
+            Self::U16 => u16::try_from(raw).ok()?.to_be_bytes().to_vec(),
+            Self::I16 => i16::try_from(raw).ok()?.to_be_bytes().to_vec(),
+            Self::U32 => u32::try_from(raw).ok()?.to_be_bytes().to_vec(),
+            Self::I32 => i32::try_from(raw).ok()?.to_be_bytes().to_vec(),
+        })
+    }
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+fn default_register_type() -> RegisterType {
+    RegisterType::U16
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Register {
+    pub name: String,
+    pub address: u16,
+    #[serde(default)]
+    pub unit: String,
+    /// Physical value = raw value * scale + offset.
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub offset: f64,
+    #[serde(rename = "type", default = "default_register_type")]
+    pub register_type: RegisterType,
+    #[serde(default)]
+    pub writable: bool,
+    #[serde(default)]
+    pub description: String,
+}
+
+impl Register {
+    /// A register that is not in the map, read as a raw `u16`.
+    pub fn unnamed(address: u16) -> Self {
+        Register {
+            name: format!("{:#06x}", address),
+            address,
+            unit: String::new(),
+            scale: default_scale(),
+            offset: 0.0,
+            register_type: default_register_type(),
+            writable: false,
+            description: String::new(),
+        }
+    }
+
+    pub fn to_physical(&self, raw: i64) -> f64 {
+        raw as f64 * self.scale + self.offset
+    }
+
+    pub fn to_raw(&self, value: f64) -> Result<i64> {
+        let raw = ((value - self.offset) / self.scale).round();
+        if !raw.is_finite() {
+            bail!(
+                "{} can't be converted to a raw value of {}",
+                value,
+                self.name
+            );
+        }
+        Ok(raw as i64)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct RegisterValue<'a> {
+    pub register: &'a Register,
+    pub raw: i64,
+}
+
+impl Display for RegisterValue<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({:#06x}) = {} {} (raw {})",
+            self.register.name,
+            self.register.address,
+            self.register.to_physical(self.raw),
+            self.register.unit,
+            self.raw
+        )
+    }
+}
+
+fn default_device_type() -> u16 {
+    3730
+}
+
+fn default_serial_number() -> u32 {
+    0xFFFFFFFF
+}
+
+fn default_read_subcommand() -> u8 {
+    READ_REGISTER
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct RegisterMap {
+    #[serde(default = "default_device_type")]
+    pub device_type: u16,
+    #[serde(default = "default_serial_number")]
+    pub serial_number: u32,
+    #[serde(default = "default_read_subcommand")]
+    pub read_subcommand: u8,
+    /// Subcommand of the `Write` telegram carrying an address and a value. Writing is only
+    /// possible when the map defines it.
+    #[serde(default)]
+    pub write_subcommand: Option<u8>,
+    #[serde(default, rename = "register")]
+    pub registers: Vec<Register>,
+}
+
+impl RegisterMap {
+    /// Loads a register map from a TOML or JSON file, depending on its extension.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("could not read register map {}", path.display()))?;
+        let map = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(&content)?,
+            Some("toml") => toml::from_str(&content)?,
+            _ => bail!(
+                "register map {} must be a .toml or .json file",
+                path.display()
+            ),
+        };
+        Ok(map)
+    }
+
+    /// Finds a register by name or by a hexadecimal (`0x0031`) or decimal address. Addresses
+    /// that are not in the map yield an unnamed register.
+    pub fn resolve(&self, name_or_addr: &str) -> Result<Register> {
+        if let Some(register) = self
+            .registers
+            .iter()
+            .find(|r| r.name.eq_ignore_ascii_case(name_or_addr))
+        {
+            return Ok(register.clone());
+        }
+
+        let address = parse_number::<u16>(name_or_addr)
+            .map_err(|_| anyhow!("unknown register {}", name_or_addr))?;
+
+        Ok(self
+            .registers
+            .iter()
+            .find(|r| r.address == address)
+            .cloned()
+            .unwrap_or_else(|| Register::unnamed(address)))
+    }
+
+    pub fn read_telegram(&self, register: &Register) -> Telegram {
+        Telegram {
+            device_type: self.device_type,
+            serial_number: self.serial_number,
+            command: Command::Read,
+            subcommand: self.read_subcommand,
+            data: register.address.to_be_bytes().to_vec(),
+        }
+    }
+
+    pub fn write_telegram(&self, register: &Register, value: f64) -> Result<Telegram> {
+        if !register.writable {
+            bail!("register {} is not writable", register.name);
+        }
+        let subcommand = self
+            .write_subcommand
+            .ok_or_else(|| anyhow!("register map has no write_subcommand"))?;
+        let raw = register.to_raw(value)?;
+        let encoded = register.register_type.encode(raw).ok_or_else(|| {
+            anyhow!(
+                "{} is out of range for {} ({:?})",
+                value,
+                register.name,
+                register.register_type
+            )
+        })?;
+
+        let mut data = register.address.to_be_bytes().to_vec();
+        data.extend(encoded);
+        Ok(Telegram {
+            device_type: self.device_type,
+            serial_number: self.serial_number,
+            command: Command::Write,
+            subcommand,
+            data,
+        })
+    }
+
+    /// Decodes the value in a reply. The value is taken from the end of the payload, so replies
+    /// with and without the echoed address are both accepted.
+    pub fn decode_reply<'a>(
+        &self,
+        register: &'a Register,
+        reply: &Telegram,
+    ) -> Result<RegisterValue<'a>> {
+        let size = register.register_type.size();
+        let start = reply.data.len().checked_sub(size).ok_or_else(|| {
+            anyhow!(
+                "reply for {} has {} data bytes, expected at least {}",
+                register.name,
+                reply.data.len(),
+                size
+            )
+        })?;
+        let raw = register
+            .register_type
+            .decode(&reply.data[start..])
+            .expect("slice has the size of the register");
+        Ok(RegisterValue { register, raw })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map() -> RegisterMap {
+        toml::from_str(
+            r#"
+            write_subcommand = 0xD3
+
+            [[register]]
+            name = "temperature"
+            address = 0x0031
+            unit = "C"
+            scale = 0.1
+            type = "i16"
+            writable = true
+
+            [[register]]
+            name = "uptime"
+            address = 0x2057
+            type = "u32"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_resolve() {
+        let map = map();
+        assert_eq!(map.resolve("Temperature").unwrap().address, 0x0031);
+        assert_eq!(map.resolve("0x2057").unwrap().name, "uptime");
+        assert_eq!(map.resolve("238").unwrap().name, "0x00ee");
+        assert_eq!(map.resolve("0X2057").unwrap().name, "uptime");
+        assert_eq!(map.resolve("8279").unwrap().name, "uptime");
+        assert!(map.resolve("0x10000").is_err());
+        assert!(map.resolve("missing").is_err());
+    }
+
+    #[test]
+    fn test_read() {
+        let map = map();
+        let register = map.resolve("temperature").unwrap();
+        assert_eq!(
+            map.read_telegram(&register).to_bytes().unwrap(),
+            vec![0x0E, 0x92, 0xFF, 0xFF, 0xFF, 0xFF, 0x06, 0x01, 0xD2, 0x00, 0x31, 0x6C, 0x5A]
+        );
+
+        let mut reply = map.read_telegram(&register);
+        reply.data.extend([0xFF, 0x06]);
+        let value = map.decode_reply(&register, &reply).unwrap();
+        assert_eq!(value.raw, -250);
+        assert_eq!(value.to_string(), "temperature (0x0031) = -25 C (raw -250)");
+    }
+
+    #[test]
+    fn test_write() {
+        let map = map();
+        let temperature = map.resolve("temperature").unwrap();
+        let telegram = map.write_telegram(&temperature, 21.5).unwrap();
+        assert_eq!(telegram.command, Command::Write);
+        assert_eq!(telegram.subcommand, 0xD3);
+        assert_eq!(telegram.data, vec![0x00, 0x31, 0x00, 0xD7]);
+
+        assert!(map.write_telegram(&temperature, 4000.0).is_err());
+        assert!(map
+            .write_telegram(&map.resolve("uptime").unwrap(), 1.0)
+            .is_err());
+    }
+}
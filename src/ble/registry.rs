@@ -0,0 +1,345 @@
+use std::{fmt::Display, fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::ble::telegram::{Command, Telegram};
+
+const BUILTIN: &str = include_str!("registry.toml");
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    U8,
+    U16be,
+    U16le,
+    U32be,
+    U32le,
+    String,
+    Bytes,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct EnumValue {
+    pub value: u32,
+    pub name: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct FieldDef {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub field_type: FieldType,
+    /// Fixed length for `string` and `bytes` fields, the rest of the payload when absent.
+    #[serde(default)]
+    pub len: Option<usize>,
+    #[serde(default)]
+    pub hex: bool,
+    /// Names for known values of an integer field.
+    #[serde(default)]
+    pub values: Vec<EnumValue>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct SubcommandDef {
+    /// Device type this definition applies to, every device when absent.
+    #[serde(default)]
+    pub device_type: Option<u16>,
+    pub command: Command,
+    pub subcommand: u8,
+    pub name: String,
+    #[serde(default)]
+    pub fields: Vec<FieldDef>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum FieldValue {
+    Unsigned { value: u32, width: usize },
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct DecodedPayload<'a> {
+    pub name: &'a str,
+    pub fields: Vec<(&'a FieldDef, FieldValue)>,
+    /// Bytes left over after all fields were decoded.
+    pub rest: Vec<u8>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct Registry {
+    #[serde(default, rename = "subcommand")]
+    entries: Vec<SubcommandDef>,
+}
+
+impl Registry {
+    /// The definitions shipped with the CLI.
+    pub fn builtin() -> Self {
+        toml::from_str(BUILTIN).expect("built-in registry is invalid")
+    }
+
+    /// Loads definitions from a TOML or JSON file, depending on its extension.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("could not read registry {}", path.display()))?;
+        let registry = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(&content)?,
+            Some("toml") => toml::from_str(&content)?,
+            _ => bail!("registry {} must be a .toml or .json file", path.display()),
+        };
+        Ok(registry)
+    }
+
+    /// The built-in definitions, extended and overridden by those in `path`.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let mut registry = Self::builtin();
+        if let Some(path) = path {
+            registry.extend(Self::from_file(path)?);
+        }
+        Ok(registry)
+    }
+
+    /// Adds the definitions of `other`, which take precedence over existing ones.
+    pub fn extend(&mut self, other: Registry) {
+        let mut entries = other.entries;
+        entries.append(&mut self.entries);
+        self.entries = entries;
+    }
+
+    /// Finds the definition for a telegram, preferring device specific definitions.
+    pub fn lookup(
+        &self,
+        device_type: u16,
+        command: Command,
+        subcommand: u8,
+    ) -> Option<&SubcommandDef> {
+        let matching = || {
+            self.entries
+                .iter()
+                .filter(move |e| e.command == command && e.subcommand == subcommand)
+        };
+        matching()
+            .find(|e| e.device_type == Some(device_type))
+            .or_else(|| matching().find(|e| e.device_type.is_none()))
+    }
+
+    /// Finds the definition of a named subcommand, preferring device specific definitions.
+    pub fn find(&self, device_type: u16, command: Command, name: &str) -> Option<&SubcommandDef> {
+        let matching = || {
+            self.entries
+                .iter()
+                .filter(move |e| e.command == command && e.name.eq_ignore_ascii_case(name))
+        };
+        matching()
+            .find(|e| e.device_type == Some(device_type))
+            .or_else(|| matching().find(|e| e.device_type.is_none()))
+    }
+
+    /// Decodes the payload of a telegram, `None` when it is unknown or doesn't fit the schema.
+    pub fn decode<'a>(&'a self, telegram: &Telegram) -> Option<DecodedPayload<'a>> {
+        self.lookup(telegram.device_type, telegram.command, telegram.subcommand)?
+            .decode(&telegram.data)
+    }
+
+    /// A one line description of the command, subcommand and payload of a telegram.
+    pub fn describe(&self, telegram: &Telegram) -> String {
+        match self.decode(telegram) {
+            Some(payload) => format!("{:?} {}", telegram.command, payload),
+            None => format!(
+                "{:?} subcommand: {}, data: {:?}",
+                telegram.command,
+                telegram.subcommand,
+                telegram.data.as_slice()
+            ),
+        }
+    }
+
+    /// Formats a telegram like its `Display` implementation, with the payload decoded.
+    pub fn display<'a>(&'a self, telegram: &'a Telegram) -> impl Display + 'a {
+        RegistryDisplay {
+            registry: self,
+            telegram,
+        }
+    }
+}
+
+struct RegistryDisplay<'a> {
+    registry: &'a Registry,
+    telegram: &'a Telegram,
+}
+
+impl Display for RegistryDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "device type: {}\nserial number: {}\n{}",
+            self.telegram.device_type,
+            self.telegram.serial_number,
+            self.registry.describe(self.telegram)
+        )
+    }
+}
+
+impl SubcommandDef {
+    pub fn decode(&self, data: &[u8]) -> Option<DecodedPayload<'_>> {
+        let mut offset = 0;
+        let mut fields = Vec::with_capacity(self.fields.len());
+        for field in &self.fields {
+            let (value, size) = field.decode(&data[offset..])?;
+            fields.push((field, value));
+            offset += size;
+        }
+        Some(DecodedPayload {
+            name: &self.name,
+            fields,
+            rest: data[offset..].to_vec(),
+        })
+    }
+}
+
+impl FieldDef {
+    fn decode(&self, data: &[u8]) -> Option<(FieldValue, usize)> {
+        let unsigned = |bytes: &[u8], value: u32| {
+            Some((
+                FieldValue::Unsigned {
+                    value,
+                    width: bytes.len(),
+                },
+                bytes.len(),
+            ))
+        };
+        match self.field_type {
+            FieldType::U8 => {
+                let b = data.get(..1)?;
+                unsigned(b, b[0] as u32)
+            }
+            FieldType::U16be => {
+                let b = data.get(..2)?;
+                unsigned(b, u16::from_be_bytes([b[0], b[1]]) as u32)
+            }
+            FieldType::U16le => {
+                let b = data.get(..2)?;
+                unsigned(b, u16::from_le_bytes([b[0], b[1]]) as u32)
+            }
+            FieldType::U32be => {
+                let b = data.get(..4)?;
+                unsigned(b, u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            }
+            FieldType::U32le => {
+                let b = data.get(..4)?;
+                unsigned(b, u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            }
+            FieldType::String => {
+                let b = data.get(..self.len.unwrap_or(data.len()))?;
+                let text = String::from_utf8_lossy(b)
+                    .trim_end_matches('\0')
+                    .to_string();
+                Some((FieldValue::Text(text), b.len()))
+            }
+            FieldType::Bytes => {
+                let b = data.get(..self.len.unwrap_or(data.len()))?;
+                Some((FieldValue::Bytes(b.to_vec()), b.len()))
+            }
+        }
+    }
+
+    pub fn format(&self, value: &FieldValue) -> String {
+        match value {
+            FieldValue::Unsigned { value, width } => {
+                if let Some(v) = self.values.iter().find(|v| v.value == *value) {
+                    v.name.clone()
+                } else if self.hex {
+                    format!("{:#0w$x}", value, w = width * 2 + 2)
+                } else {
+                    value.to_string()
+                }
+            }
+            FieldValue::Text(text) => format!("{:?}", text),
+            FieldValue::Bytes(bytes) => format!("{:?}", bytes),
+        }
+    }
+}
+
+impl Display for DecodedPayload<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts: Vec<String> = self
+            .fields
+            .iter()
+            .map(|(def, value)| format!("{}={}", def.name, def.format(value)))
+            .collect();
+        if !self.rest.is_empty() {
+            parts.push(format!("{:?}", self.rest));
+        }
+        if parts.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{}({})", self.name, parts.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::prefab::greet;
+
+    fn telegram(command: Command, subcommand: u8, data: Vec<u8>) -> Telegram {
+        Telegram {
+            command,
+            subcommand,
+            data,
+            ..greet()
+        }
+    }
+
+    #[test]
+    fn test_builtin() {
+        let registry = Registry::builtin();
+        assert_eq!(
+            registry.describe(&telegram(Command::Read, 210, vec![0x00, 0x31])),
+            "Read ReadRegister(addr=0x0031)"
+        );
+        assert_eq!(
+            registry.describe(&telegram(Command::Read, 204, vec![])),
+            "Read Greet"
+        );
+        assert_eq!(
+            registry.describe(&telegram(Command::Write, 210, vec![0, 0, 0x25, 0x80])),
+            "Write ChangeBaudrate(baudrate=9600)"
+        );
+        assert_eq!(
+            registry.describe(&telegram(Command::Read, 210, vec![0x00])),
+            "Read subcommand: 210, data: [0]"
+        );
+    }
+
+    #[test]
+    fn test_device_specific_override() {
+        let mut registry = Registry::builtin();
+        registry.extend(
+            toml::from_str(
+                r#"
+                [[subcommand]]
+                device_type = 3730
+                command = "Read"
+                subcommand = 50
+                name = "Mode"
+                fields = [
+                    { name = "mode", type = "u8", values = [{ value = 1, name = "Auto" }] },
+                    { name = "label", type = "string" },
+                ]
+                "#,
+            )
+            .unwrap(),
+        );
+
+        assert_eq!(
+            registry.describe(&telegram(Command::Read, 50, vec![1, b'h', b'i', 0])),
+            "Read Mode(mode=Auto, label=\"hi\")"
+        );
+        let mut other_device = telegram(Command::Read, 50, vec![1]);
+        other_device.device_type = 3793;
+        assert_eq!(registry.decode(&other_device), None);
+    }
+}
@@ -0,0 +1,178 @@
+use anyhow::{anyhow, Result};
+use tokio::time::Duration;
+
+use crate::ble::{
+    client::is_response,
+    expect::Expectation,
+    framer::TelegramFramer,
+    recorder::{Direction, Record, STATUS_OK, STATUS_RAW},
+    telegram::{parse_hex, Telegram},
+    telegram_sequence::{EventSequence, Step},
+};
+
+/// How the pauses between replayed requests are chosen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timing {
+    /// The recorded pauses divided by `speed`, so 2.0 replays twice as fast.
+    Recorded {
+        speed: f64,
+    },
+    Fixed(Duration),
+}
+
+/// The recorded requests as a sequence. Every request is expected to get exactly the response
+/// that was recorded for it, requests that went unanswered are sent without expectation.
+/// Received notifications are reassembled into telegrams, control point frames and frames that
+/// failed to decode are left out.
+pub fn sequence(records: &[Record], timing: Timing) -> Result<EventSequence> {
+    let mut frames = Vec::new();
+    let mut framer = TelegramFramer::new();
+    for (i, record) in records.iter().enumerate() {
+        if record.status == STATUS_RAW {
+            continue;
+        }
+        let Some(data) = &record.data else {
+            continue;
+        };
+        let bytes =
+            parse_hex(data).map_err(|t| anyhow!("record {}: invalid hex byte {}", i + 1, t))?;
+        match record.direction {
+            Direction::Tx if record.status == STATUS_OK => {
+                let telegram = Telegram::from_bytes(&bytes)
+                    .map_err(|e| anyhow!("record {}: invalid telegram: {}", i + 1, e))?;
+                frames.push((i, record, telegram));
+            }
+            Direction::Tx => {}
+            // A telegram is received with the notification that completes it.
+            Direction::Rx => {
+                framer.push(&bytes);
+                while let Some(result) = framer.next_telegram() {
+                    if let Ok(telegram) = result {
+                        frames.push((i, record, telegram));
+                    }
+                }
+            }
+        }
+    }
+
+    let mut steps = Vec::new();
+    let mut previous: Option<u64> = None;
+    for (i, (index, record, request)) in frames.iter().enumerate() {
+        if record.direction == Direction::Tx {
+            let response = frames[i + 1..]
+                .iter()
+                .take_while(|(_, r, _)| r.direction == Direction::Rx)
+                .find(|(_, _, response)| is_response(request, response));
+            // Wait from the last frame before this request, the response time isn't a pause.
+            let pause = previous.map_or(0, |p| record.timestamp_us.saturating_sub(p));
+            steps.push(Step {
+                name: Some(format!("record {}", index + 1)),
+                telegram: request.clone(),
+                delay: match timing {
+                    Timing::Recorded { speed } => Duration::from_micros(pause).div_f64(speed),
+                    Timing::Fixed(delay) => delay,
+                },
+                expect: response
+                    .map(|(_, _, r)| vec![Expectation::Exact(r.clone())])
+                    .unwrap_or_default(),
+            });
+        }
+        previous = Some(record.timestamp_us);
+    }
+    Ok(EventSequence { steps })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(timestamp_ms: u64, direction: Direction, data: &str, status: &str) -> Record {
+        Record {
+            timestamp_us: timestamp_ms * 1000,
+            direction,
+            characteristic: String::new(),
+            data: Some(data.to_string()),
+            status: status.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_sequence() {
+        let records = vec![
+            record(
+                0,
+                Direction::Tx,
+                "0E 92 FF FF FF FF 04 01 CC B1 21",
+                STATUS_OK,
+            ),
+            record(
+                10,
+                Direction::Rx,
+                "0E 92 FF FF FF FF 04 01 65 71 5F",
+                STATUS_OK,
+            ),
+            record(
+                12,
+                Direction::Rx,
+                "0E 92 FF FF FF FF 04 01 CC B1 21",
+                STATUS_OK,
+            ),
+            record(20, Direction::Tx, "02 80 25 00 00 00 00", STATUS_RAW),
+            record(
+                100,
+                Direction::Rx,
+                "0E 92 FF FF FF FF 04 01 CC B1 22",
+                "invalid checksum",
+            ),
+            record(
+                112,
+                Direction::Tx,
+                "0E 92 FF FF FF FF 04 01 65 71 5F",
+                STATUS_OK,
+            ),
+        ];
+
+        let sequence = sequence(&records, Timing::Recorded { speed: 2.0 }).unwrap();
+        assert_eq!(sequence.steps.len(), 2);
+        assert_eq!(sequence.steps[0].telegram.subcommand, 204);
+        assert_eq!(sequence.steps[1].name.as_deref(), Some("record 6"));
+        assert_eq!(sequence.steps[0].delay, Duration::ZERO);
+        assert_eq!(
+            sequence.steps[0].expect,
+            vec![Expectation::Exact(sequence.steps[0].telegram.clone())]
+        );
+        // 100 ms since the greet response, replayed twice as fast.
+        assert_eq!(sequence.steps[1].delay, Duration::from_millis(50));
+        assert!(sequence.steps[1].expect.is_empty());
+
+        let fixed = Timing::Fixed(Duration::from_millis(5));
+        assert_eq!(
+            super::sequence(&records, fixed).unwrap().steps[1].delay,
+            Duration::from_millis(5)
+        );
+    }
+
+    #[test]
+    fn test_split_notifications() {
+        let records = vec![
+            record(
+                0,
+                Direction::Tx,
+                "0E 92 FF FF FF FF 04 01 CC B1 21",
+                STATUS_OK,
+            ),
+            record(5, Direction::Rx, "0E 92 FF FF FF", "telegram too short"),
+            record(
+                6,
+                Direction::Rx,
+                "FF 04 01 CC B1 21 0E",
+                "telegram too short",
+            ),
+        ];
+        let sequence = sequence(&records, Timing::Fixed(Duration::ZERO)).unwrap();
+        assert_eq!(
+            sequence.steps[0].expect,
+            vec![Expectation::Exact(sequence.steps[0].telegram.clone())]
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use tokio::time::Duration;
+
+use crate::ble::{
+    telegram::format_hex,
+    telegram_sequence::{Outcome, Step},
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase", tag = "status", content = "message")]
+pub enum Status {
+    Passed,
+    /// The response didn't meet the expectations.
+    Failed(String),
+    /// The request failed and the step has no expectations.
+    Error(String),
+    /// The step wasn't sent because the transport closed or failed. Steps with expectations are
+    /// reported as errors instead, their checks never ran.
+    Skipped,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TestCase {
+    pub name: String,
+    pub request: String,
+    pub response: Option<String>,
+    pub time_s: f64,
+    #[serde(flatten)]
+    pub status: Status,
+}
+
+/// The steps of a run as test cases, written as JUnit XML or JSON.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TestReport {
+    pub name: String,
+    pub tests: usize,
+    pub failures: usize,
+    pub errors: usize,
+    pub skipped: usize,
+    pub time_s: f64,
+    pub cases: Vec<TestCase>,
+}
+
+impl TestReport {
+    pub fn new(name: &str, steps: &[Step], outcomes: &[Outcome], duration: Duration) -> Self {
+        let cases: Vec<TestCase> = steps
+            .iter()
+            .enumerate()
+            .map(|(i, step)| {
+                let outcome = outcomes.get(i);
+                let status = match outcome {
+                    None if step.expect.is_empty() => Status::Skipped,
+                    None => Status::Error("not sent, the transport closed or failed".to_string()),
+                    Some(Outcome {
+                        check: Some(Err(mismatch)),
+                        ..
+                    }) => Status::Failed(mismatch.clone()),
+                    Some(Outcome {
+                        check: None,
+                        result: Err(e),
+                        ..
+                    }) => Status::Error(e.to_string()),
+                    Some(_) => Status::Passed,
+                };
+                TestCase {
+                    name: match &step.name {
+                        Some(name) => format!("{} ({})", name, i + 1),
+                        None => format!("step {}", i + 1),
+                    },
+                    request: format_hex(&step.telegram.to_bytes().unwrap_or_default()),
+                    response: outcome
+                        .and_then(|o| o.result.as_ref().ok())
+                        .map(|r| format_hex(&r.to_bytes().unwrap_or_default())),
+                    time_s: outcome.map_or(0.0, |o| o.elapsed.as_secs_f64()),
+                    status,
+                }
+            })
+            .collect();
+
+        let count = |f: fn(&Status) -> bool| cases.iter().filter(|c| f(&c.status)).count();
+        TestReport {
+            name: name.to_string(),
+            tests: cases.len(),
+            failures: count(|s| matches!(s, Status::Failed(_))),
+            errors: count(|s| matches!(s, Status::Error(_))),
+            skipped: count(|s| matches!(s, Status::Skipped)),
+            time_s: duration.as_secs_f64(),
+            cases,
+        }
+    }
+
+    pub fn to_junit(&self) -> String {
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml += &format!(
+            "<testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n",
+            escape(&self.name),
+            self.tests,
+            self.failures,
+            self.errors,
+            self.skipped,
+            self.time_s
+        );
+        for case in &self.cases {
+            xml += &format!(
+                "  <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\">\n",
+                escape(&case.name),
+                escape(&self.name),
+                case.time_s
+            );
+            match &case.status {
+                Status::Passed => {}
+                Status::Failed(message) => {
+                    xml += &format!("    <failure message=\"{}\"/>\n", escape(message))
+                }
+                Status::Error(message) => {
+                    xml += &format!("    <error message=\"{}\"/>\n", escape(message))
+                }
+                Status::Skipped => xml += "    <skipped/>\n",
+            }
+            xml += &format!("    <system-out>request: {}", case.request);
+            if let Some(response) = &case.response {
+                xml += &format!("\nresponse: {}", response);
+            }
+            xml += "</system-out>\n  </testcase>\n";
+        }
+        xml += "</testsuite>\n";
+        xml
+    }
+
+    /// Writes the report as JUnit XML or JSON, depending on the extension of `path`.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let content = match path.extension().and_then(|e| e.to_str()) {
+            Some("xml") => self.to_junit(),
+            Some("json") => serde_json::to_string_pretty(self)?,
+            _ => bail!("report {} must be a .xml or .json file", path.display()),
+        };
+        fs::write(path, content)
+            .with_context(|| format!("could not write report {}", path.display()))
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::{client::RequestError, expect::Expectation, prefab::greet};
+
+    fn step(name: Option<&str>) -> Step {
+        Step {
+            name: name.map(String::from),
+            telegram: greet(),
+            delay: Duration::ZERO,
+            expect: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let steps = vec![
+            step(Some("greet")),
+            step(Some("<bad>")),
+            step(None),
+            step(None),
+        ];
+        let outcomes = vec![
+            Outcome {
+                result: Ok(steps[0].telegram.clone()),
+                elapsed: Duration::from_millis(5),
+                check: Some(Ok(())),
+            },
+            Outcome {
+                result: Ok(steps[1].telegram.clone()),
+                elapsed: Duration::from_millis(5),
+                check: Some(Err("expected \"x\"".to_string())),
+            },
+            Outcome {
+                result: Err(RequestError::Closed),
+                elapsed: Duration::ZERO,
+                check: None,
+            },
+        ];
+        let report = TestReport::new("seq", &steps, &outcomes, Duration::from_secs(1));
+        assert_eq!(
+            (report.tests, report.failures, report.errors, report.skipped),
+            (4, 1, 1, 1)
+        );
+        assert_eq!(report.cases[0].name, "greet (1)");
+        assert_eq!(report.cases[3].name, "step 4");
+
+        let xml = report.to_junit();
+        assert!(xml.contains(
+            "<testsuite name=\"seq\" tests=\"4\" failures=\"1\" errors=\"1\" skipped=\"1\" time=\"1.000\">"
+        ));
+        assert!(xml.contains("<testcase name=\"&lt;bad&gt; (2)\""));
+        assert!(xml.contains("<failure message=\"expected &quot;x&quot;\"/>"));
+        assert!(xml.contains("<error message=\"transport closed\"/>"));
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["cases"][1]["status"], "failed");
+        assert_eq!(json["cases"][1]["message"], "expected \"x\"");
+        assert_eq!(
+            json["cases"][0]["response"],
+            "0E 92 FF FF FF FF 04 01 CC B1 21"
+        );
+
+        let mut checked = step(None);
+        checked.expect = vec![Expectation::Subcommand(204)];
+        let report = TestReport::new("seq", &[checked], &[], Duration::ZERO);
+        assert_eq!((report.errors, report.skipped), (1, 0));
+    }
+}
@@ -0,0 +1,465 @@
+use std::{collections::BTreeMap, fmt::Display, fs, path::Path};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use tokio::time::Duration;
+
+use crate::args::parse_number;
+use crate::ble::{
+    expect::Expectation,
+    registry::Registry,
+    telegram::{parse_hex, Command, Telegram, ANY_DEVICE_TYPE, ANY_SERIAL_NUMBER},
+    telegram_sequence::{EventSequence, Step},
+};
+
+/// A number, or text that may refer to variables as `${name}`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Number(u64),
+    Text(String),
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Text(t) => write!(f, "{}", t),
+        }
+    }
+}
+
+/// The checks on the response of a step, all given checks have to pass.
+#[derive(Debug, Clone, PartialEq, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ExpectDef {
+    /// The complete response telegram.
+    pub hex: Option<String>,
+    /// See [`Expectation::pattern`].
+    pub pattern: Option<String>,
+    /// Subcommand number or name from the registry.
+    pub subcommand: Option<Value>,
+    pub data_prefix: Option<String>,
+    /// Response bytes compared under `mask`.
+    pub value: Option<String>,
+    pub mask: Option<String>,
+}
+
+/// A step of a script, either a complete telegram in `hex` or its fields. `device_type` and
+/// `serial` override the address of a hex telegram.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StepDef {
+    pub name: Option<String>,
+    pub hex: Option<String>,
+    pub device_type: Option<Value>,
+    pub serial: Option<Value>,
+    /// read, write, execute or the command byte.
+    pub command: Option<String>,
+    /// Subcommand number or name from the registry.
+    pub subcommand: Option<Value>,
+    /// Payload in hex.
+    pub data: Option<String>,
+    /// Pause before the step, the default delay of the script when absent.
+    pub delay_ms: Option<Value>,
+    /// Times the step is sent in a row.
+    pub repeat: Option<Value>,
+    pub expect: Option<ExpectDef>,
+}
+
+/// A test sequence loaded from a TOML file:
+///
+/// ```toml
+/// delay_ms = 100  # pause between steps
+/// repeat = 2      # times the whole script runs
+///
+/// [variables]
+/// serial = "0x00BC614E"
+///
+/// [[step]]
+/// name = "greet"
+/// command = "read"
+/// subcommand = "greet"
+/// expect = { pattern = "0E 92 ?? ?? ?? ?? 0D 01 CC *" }
+///
+/// [[step]]
+/// hex = "0E 92 FF FF FF FF 04 01 65 71 5F"
+/// serial = "${serial}"
+/// repeat = 10
+/// ```
+///
+/// Variables are substituted as text in every string of a step. Steps in field form are sent to
+/// the `device_type` and `serial` variables when set, to every device otherwise.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Script {
+    #[serde(default)]
+    pub delay_ms: u64,
+    #[serde(default = "default_repeat")]
+    pub repeat: usize,
+    #[serde(default)]
+    pub variables: BTreeMap<String, Value>,
+    #[serde(default, rename = "step")]
+    pub steps: Vec<StepDef>,
+}
+
+fn default_repeat() -> usize {
+    1
+}
+
+impl Script {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("could not read script {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("invalid script {}", path.display()))
+    }
+
+    /// Sets a variable from `name=value`, e.g. given on the command line.
+    pub fn set_variable(&mut self, assignment: &str) -> Result<()> {
+        let (name, value) = assignment
+            .split_once('=')
+            .ok_or_else(|| anyhow!("variable {} is not of the form name=value", assignment))?;
+        self.variables.insert(
+            name.trim().to_string(),
+            Value::Text(value.trim().to_string()),
+        );
+        Ok(())
+    }
+
+    /// The steps as a sequence, `repeat` times in a row.
+    pub fn compile(&self, registry: &Registry) -> Result<EventSequence> {
+        let mut steps = Vec::new();
+        for (i, def) in self.steps.iter().enumerate() {
+            let label = match &def.name {
+                Some(name) => format!("step {} ({})", i + 1, name),
+                None => format!("step {}", i + 1),
+            };
+            let (step, repeat) = self.compile_step(def, registry).context(label)?;
+            steps.extend(std::iter::repeat_n(step, repeat));
+        }
+
+        let count = steps.len();
+        Ok(EventSequence {
+            steps: steps
+                .into_iter()
+                .cycle()
+                .take(count * self.repeat)
+                .collect(),
+        })
+    }
+
+    fn compile_step(&self, def: &StepDef, registry: &Registry) -> Result<(Step, usize)> {
+        let device_type = def
+            .device_type
+            .as_ref()
+            .or(self.variables.get("device_type"))
+            .map(|v| self.number::<u16>(v))
+            .transpose()
+            .context("invalid device type")?;
+        let serial_number = def
+            .serial
+            .as_ref()
+            .or(self.variables.get("serial"))
+            .map(|v| self.number::<u32>(v))
+            .transpose()
+            .context("invalid serial")?;
+
+        let telegram = match &def.hex {
+            Some(hex) => {
+                if def.command.is_some() || def.subcommand.is_some() || def.data.is_some() {
+                    bail!("hex can't be combined with command, subcommand or data");
+                }
+                let bytes = parse_hex(&self.substitute(hex)?)
+                    .map_err(|t| anyhow!("invalid hex byte {}", t))?;
+                let mut telegram = Telegram::from_bytes(&bytes)?;
+                // Only an explicit address overrides the one in the telegram.
+                if def.device_type.is_some() || def.serial.is_some() {
+                    telegram.device_type = device_type.unwrap_or(telegram.device_type);
+                    telegram.serial_number = serial_number.unwrap_or(telegram.serial_number);
+                }
+                telegram
+            }
+            None => {
+                let command: Command = self
+                    .substitute(
+                        def.command
+                            .as_deref()
+                            .ok_or_else(|| anyhow!("missing command"))?,
+                    )?
+                    .parse()
+                    .map_err(|e| anyhow!("{}", e))?;
+                let subcommand = def
+                    .subcommand
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("missing subcommand"))?;
+                let device_type = device_type.unwrap_or(ANY_DEVICE_TYPE);
+                let subcommand = self.subcommand(subcommand, device_type, command, registry)?;
+                let data = self.substitute(def.data.as_deref().unwrap_or(""))?;
+                Telegram {
+                    device_type,
+                    serial_number: serial_number.unwrap_or(ANY_SERIAL_NUMBER),
+                    command,
+                    subcommand,
+                    data: parse_hex(&data).map_err(|t| anyhow!("invalid hex byte {}", t))?,
+                }
+            }
+        };
+        telegram.to_bytes()?;
+
+        let expect = match &def.expect {
+            Some(expect) => self
+                .compile_expect(expect, &telegram, registry)
+                .context("invalid expectation")?,
+            None => Vec::new(),
+        };
+
+        let delay = match &def.delay_ms {
+            Some(delay) => self.number::<u64>(delay).context("invalid delay")?,
+            None => self.delay_ms,
+        };
+        let repeat = match &def.repeat {
+            Some(repeat) => self.number::<usize>(repeat).context("invalid repeat")?,
+            None => 1,
+        };
+
+        let step = Step {
+            name: def.name.clone(),
+            telegram,
+            delay: Duration::from_millis(delay),
+            expect,
+        };
+        Ok((step, repeat))
+    }
+
+    /// The expectations on the response to `request`, subcommand names are looked up for its
+    /// command and device type.
+    fn compile_expect(
+        &self,
+        def: &ExpectDef,
+        request: &Telegram,
+        registry: &Registry,
+    ) -> Result<Vec<Expectation>> {
+        let text = |text: &Option<String>| text.as_deref().map(|t| self.substitute(t)).transpose();
+
+        let mut expect = Vec::new();
+        if let Some(hex) = text(&def.hex)? {
+            expect.push(Expectation::exact(&hex).map_err(|e| anyhow!(e))?);
+        }
+        if let Some(pattern) = text(&def.pattern)? {
+            expect.push(Expectation::pattern(&pattern).map_err(|e| anyhow!(e))?);
+        }
+        if let Some(subcommand) = &def.subcommand {
+            expect.push(Expectation::Subcommand(self.subcommand(
+                subcommand,
+                request.device_type,
+                request.command,
+                registry,
+            )?));
+        }
+        if let Some(prefix) = text(&def.data_prefix)? {
+            let prefix = parse_hex(&prefix).map_err(|t| anyhow!("invalid hex byte {}", t))?;
+            expect.push(Expectation::DataPrefix(prefix));
+        }
+        match (text(&def.value)?, text(&def.mask)?) {
+            (Some(value), Some(mask)) => {
+                expect.push(Expectation::mask(&value, &mask).map_err(|e| anyhow!(e))?)
+            }
+            (None, None) => {}
+            _ => bail!("value and mask have to be given together"),
+        }
+        Ok(expect)
+    }
+
+    /// A subcommand number, or the `command` subcommand of that name in the registry.
+    fn subcommand(
+        &self,
+        value: &Value,
+        device_type: u16,
+        command: Command,
+        registry: &Registry,
+    ) -> Result<u8> {
+        if let Ok(subcommand) = self.number::<u8>(value) {
+            return Ok(subcommand);
+        }
+        let name = self.substitute(&value.to_string())?;
+        Ok(registry
+            .find(device_type, command, &name)
+            .ok_or_else(|| anyhow!("unknown {:?} subcommand {}", command, name))?
+            .subcommand)
+    }
+
+    fn number<T: TryFrom<u64>>(&self, value: &Value) -> Result<T> {
+        let text = match value {
+            Value::Number(n) => n.to_string(),
+            Value::Text(t) => self.substitute(t)?,
+        };
+        parse_number(text.trim()).map_err(|e| anyhow!("{}: {}", text, e))
+    }
+
+    /// Replaces every `${name}` in `text` with the value of the variable.
+    fn substitute(&self, text: &str) -> Result<String> {
+        let mut result = String::new();
+        let mut rest = text;
+        while let Some(start) = rest.find("${") {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| anyhow!("unterminated variable in {}", text))?;
+            let name = &rest[start + 2..start + end];
+            let value = self
+                .variables
+                .get(name)
+                .ok_or_else(|| anyhow!("unknown variable {}", name))?;
+            result.push_str(&rest[..start]);
+            result.push_str(&value.to_string());
+            rest = &rest[start + end + 1..];
+        }
+        result.push_str(rest);
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = r#"
+        delay_ms = 100
+        repeat = 2
+
+        [variables]
+        serial = "0x00BC614E"
+        greeting = "42 6C 75 65"
+
+        [[step]]
+        name = "greet"
+        command = "read"
+        subcommand = "greet"
+        expect = { pattern = "0E 92 00 BC 61 4E 0D 01 CC ${greeting} *" }
+
+        [[step]]
+        command = "read"
+        subcommand = 101
+        data = "AA 55"
+        repeat = 0
+        expect = { subcommand = "Testbench", data_prefix = "AA", value = "0E", mask = "FF" }
+
+        [[step]]
+        hex = "0E 92 FF FF FF FF 04 01 65 71 5F"
+        delay_ms = "${delay}"
+        repeat = 3
+    "#;
+
+    #[test]
+    fn test_compile() {
+        let mut script: Script = toml::from_str(SCRIPT).unwrap();
+        script.set_variable("delay=0x10").unwrap();
+        let sequence = script.compile(&Registry::builtin()).unwrap();
+        assert_eq!(sequence.steps.len(), 8);
+
+        let greet = &sequence.steps[0];
+        assert_eq!(greet.name.as_deref(), Some("greet"));
+        assert_eq!(greet.telegram.device_type, ANY_DEVICE_TYPE);
+        assert_eq!(greet.telegram.serial_number, 12345678);
+        assert_eq!(greet.telegram.subcommand, 204);
+        assert_eq!(greet.delay, Duration::from_millis(100));
+        assert_eq!(greet.expect.len(), 1);
+
+        // The address of a hex telegram is kept.
+        let testbench = &sequence.steps[1];
+        assert_eq!(testbench.telegram.device_type, 3730);
+        assert_eq!(testbench.telegram.serial_number, ANY_SERIAL_NUMBER);
+        assert_eq!(testbench.telegram.subcommand, 101);
+        assert_eq!(testbench.delay, Duration::from_millis(16));
+        assert_eq!(sequence.steps[1..4], sequence.steps[5..8]);
+
+        let mut script: Script = toml::from_str(SCRIPT).unwrap();
+        script.steps[1].repeat = None;
+        script.set_variable("delay=0").unwrap();
+        let sequence = script.compile(&Registry::builtin()).unwrap();
+        assert_eq!(
+            sequence.steps[1].expect,
+            vec![
+                Expectation::Subcommand(101),
+                Expectation::DataPrefix(vec![0xAA]),
+                Expectation::Mask {
+                    value: vec![0x0E],
+                    mask: vec![0xFF]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let script: Script = toml::from_str(SCRIPT).unwrap();
+        let error = script.compile(&Registry::builtin()).unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "step 3: invalid delay: unknown variable delay"
+        );
+
+        let script: Script =
+            toml::from_str("[[step]]\nname = \"bad\"\nhex = \"0E 92 FF FF FF FF 04 01 65 71 5E\"")
+                .unwrap();
+        let error = script.compile(&Registry::builtin()).unwrap_err();
+        assert!(format!("{:#}", error).starts_with("step 1 (bad): invalid checksum"));
+
+        assert!(toml::from_str::<Script>("[[step]]\nbogus = 1").is_err());
+
+        let script: Script = toml::from_str(
+            "[[step]]\nhex = \"0E 92 FF FF FF FF 04 01 65 71 5F\"\nexpect = { mask = \"FF\" }",
+        )
+        .unwrap();
+        let error = script.compile(&Registry::builtin()).unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "step 1: invalid expectation: value and mask have to be given together"
+        );
+    }
+
+    #[test]
+    fn test_subcommand_names() {
+        let script: Script =
+            toml::from_str("[[step]]\ncommand = \"write\"\nsubcommand = \"ReadRegister\"").unwrap();
+        let error = script.compile(&Registry::builtin()).unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "step 1: unknown Write subcommand ReadRegister"
+        );
+
+        // A name defined for another device type resolves to the generic definition.
+        let mut registry = Registry::builtin();
+        registry.extend(
+            toml::from_str(
+                r#"
+                [[subcommand]]
+                device_type = 3793
+                command = "Read"
+                subcommand = 150
+                name = "Greet"
+                "#,
+            )
+            .unwrap(),
+        );
+        let script: Script = toml::from_str(
+            r#"
+            [[step]]
+            command = "read"
+            subcommand = "greet"
+            expect = { subcommand = "greet" }
+
+            [[step]]
+            device_type = 3793
+            command = "read"
+            subcommand = "greet"
+            expect = { subcommand = "greet" }
+            "#,
+        )
+        .unwrap();
+        let sequence = script.compile(&registry).unwrap();
+        assert_eq!(sequence.steps[0].telegram.subcommand, 204);
+        assert_eq!(sequence.steps[0].expect, vec![Expectation::Subcommand(204)]);
+        assert_eq!(sequence.steps[1].telegram.subcommand, 150);
+        assert_eq!(sequence.steps[1].expect, vec![Expectation::Subcommand(150)]);
+    }
+}
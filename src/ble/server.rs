@@ -0,0 +1,556 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{anyhow, bail, Result};
+use colored::Colorize;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+    time::{sleep, Duration},
+};
+
+use crate::ble::{
+    client::{Client, RequestError},
+    control_point,
+    framing::{FrameReader, Framing, StreamFraming},
+    registry::Registry,
+    telegram::{Command, Telegram},
+    transport::TelegramTransport,
+};
+use crate::protocol::{Baudrate, CHANGE_BAUDRATE};
+
+/// Requests waiting for the link, clients wait once the queue is full.
+pub const QUEUE_DEPTH: usize = 32;
+
+static NEXT_CLIENT: AtomicUsize = AtomicUsize::new(1);
+
+/// A number for a new client, unique across all listeners.
+pub fn next_client_id() -> usize {
+    NEXT_CLIENT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Which clients receive the notifications that don't answer a request, such as events and
+/// late responses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Routing {
+    #[default]
+    All,
+    /// The client of the latest request, whose response may still arrive late.
+    Owner,
+    /// Nobody, they are only logged.
+    None,
+}
+
+impl Routing {
+    pub const ALL: [Routing; 3] = [Self::All, Self::Owner, Self::None];
+}
+
+impl FromStr for Routing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|r| r.to_string() == s)
+            .ok_or_else(|| format!("unknown routing {}, expected one of all, owner, none", s))
+    }
+}
+
+impl Display for Routing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::All => "all",
+            Self::Owner => "owner",
+            Self::None => "none",
+        })
+    }
+}
+
+/// The connected clients that telegrams can be pushed to, shared by the client tasks and the
+/// link.
+#[derive(Debug, Clone, Default)]
+pub struct Clients {
+    routing: Routing,
+    outgoing: Arc<Mutex<BTreeMap<usize, mpsc::UnboundedSender<Telegram>>>>,
+}
+
+impl Clients {
+    pub fn new(routing: Routing) -> Self {
+        Clients {
+            routing,
+            ..Default::default()
+        }
+    }
+
+    fn register(&self, id: usize) -> mpsc::UnboundedReceiver<Telegram> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.outgoing.lock().unwrap().insert(id, sender);
+        receiver
+    }
+
+    fn unregister(&self, id: usize) {
+        self.outgoing.lock().unwrap().remove(&id);
+    }
+
+    /// Queues a telegram for one client, `false` when it isn't connected.
+    pub fn send(&self, id: usize, telegram: Telegram) -> bool {
+        match self.outgoing.lock().unwrap().get(&id) {
+            Some(sender) => sender.send(telegram).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Pushes a notification to the clients selected by the routing, `owner` being the client of
+    /// the latest request. Returns the clients it was queued for.
+    pub fn notify(&self, owner: Option<usize>, telegram: &Telegram) -> Vec<usize> {
+        match (self.routing, owner) {
+            (Routing::All, _) => {
+                let outgoing = self.outgoing.lock().unwrap();
+                outgoing
+                    .iter()
+                    .filter(|(_, sender)| sender.send(telegram.clone()).is_ok())
+                    .map(|(&id, _)| id)
+                    .collect()
+            }
+            (Routing::Owner, Some(owner)) => match self.send(owner, telegram.clone()) {
+                true => vec![owner],
+                false => Vec::new(),
+            },
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// A request of a TCP client, queued for the link.
+pub struct Request {
+    pub client: usize,
+    pub telegram: Telegram,
+    /// Receives the response, `None` when the device didn't answer. Connected [`Clients`] get
+    /// it pushed by the link as well, in order with the notifications.
+    pub respond: oneshot::Sender<Option<Telegram>>,
+}
+
+/// Queues a request for the link and waits for its response. Fails when the link closed.
+pub async fn submit(
+    requests: &mpsc::Sender<Request>,
+    client: usize,
+    telegram: Telegram,
+) -> Result<Option<Telegram>> {
+    let (respond, response) = oneshot::channel();
+    let request = Request {
+        client,
+        telegram,
+        respond,
+    };
+    requests
+        .send(request)
+        .await
+        .map_err(|_| anyhow!("link closed"))?;
+    response.await.map_err(|_| anyhow!("link closed"))
+}
+
+/// Accepts clients and queues their requests on `requests`. Every client is served by its own
+/// task, numbered in the order they connected.
+pub async fn accept(
+    listener: TcpListener,
+    requests: mpsc::Sender<Request>,
+    framing: StreamFraming,
+    clients: Clients,
+) -> Result<()> {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tokio::spawn(serve_client(
+                    next_client_id(),
+                    stream,
+                    peer,
+                    requests.clone(),
+                    framing,
+                    clients.clone(),
+                ));
+            }
+            Err(e) => {
+                eprintln!("Failed to accept client: {}", e);
+                sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+/// Reads requests and writes the responses and notifications back in the given framing, until
+/// the client disconnects.
+async fn serve_client(
+    id: usize,
+    stream: TcpStream,
+    peer: SocketAddr,
+    requests: mpsc::Sender<Request>,
+    framing: StreamFraming,
+    clients: Clients,
+) {
+    println!("[{}] Client connected from {}", id, peer);
+    let (reader, writer) = stream.into_split();
+    let outgoing = clients.register(id);
+    let mut reader = FrameReader::new(reader, framing.request);
+    let read = async {
+        loop {
+            let telegram = match reader.read().await {
+                Ok(Some(Ok(t))) => t,
+                Ok(Some(Err(e))) => {
+                    println!("[{}]    Error in request {}", id, e);
+                    continue;
+                }
+                Ok(None) => return "closed by client".to_string(),
+                Err(e) => return e.to_string(),
+            };
+            // The link queues the response itself, in order with the notifications.
+            if let Err(e) = submit(&requests, id, telegram).await {
+                return e.to_string();
+            }
+        }
+    };
+    let reason = tokio::select! {
+        reason = read => reason,
+        reason = write_telegrams(writer, framing.response, outgoing) => reason,
+    };
+    clients.unregister(id);
+    println!("[{}] Client disconnected: {}", id, reason);
+}
+
+/// Writes the telegrams queued for a client, returns why it stopped.
+async fn write_telegrams(
+    mut writer: OwnedWriteHalf,
+    framing: Framing,
+    mut outgoing: mpsc::UnboundedReceiver<Telegram>,
+) -> String {
+    while let Some(telegram) = outgoing.recv().await {
+        let bytes = framing.encode(&telegram).unwrap_or_default();
+        if let Err(e) = writer.write_all(&bytes).await {
+            return e.to_string();
+        }
+    }
+    "removed".to_string()
+}
+
+/// Forwards the queued requests to the device one at a time, so clients never interleave on
+/// the link, and pushes the notifications that answer no request to the clients. Runs until the
+/// link closes.
+pub async fn serve_link<T: TelegramTransport>(
+    client: &mut Client<T>,
+    mut requests: mpsc::Receiver<Request>,
+    registry: &Registry,
+    control_timeout: Duration,
+    clients: &Clients,
+) -> Result<()> {
+    let mut owner = None;
+    loop {
+        tokio::select! {
+            request = requests.recv() => {
+                let Some(request) = request else {
+                    return Ok(());
+                };
+                owner = Some(request.client);
+                let label = format!("[{}]", request.client);
+                let response =
+                    forward(client, &label, &request.telegram, registry, control_timeout).await?;
+                // Strays arrived before the response, so they are pushed first.
+                for stray in client.take_strays() {
+                    clients.notify(owner, &stray);
+                }
+                if let Some(response) = &response {
+                    clients.send(request.client, response.clone());
+                }
+                // The client may have disconnected meanwhile.
+                let _ = request.respond.send(response);
+            }
+            notification = client.notification() => match notification {
+                Some(Ok(telegram)) => {
+                    let recipients = clients.notify(owner, &telegram);
+                    println!(
+                        "[link] {} to {:?}: {}",
+                        "Notification".yellow(),
+                        recipients,
+                        registry.display(&telegram)
+                    );
+                }
+                Some(Err(e)) => println!("[link]    Error in notification {}", e),
+                None => bail!("link to the device closed"),
+            },
+        }
+    }
+}
+
+/// Sends a request to the device and returns its response, if one arrives in time. The log lines
+/// start with `label`. Strays are logged and left for [`Client::take_strays`]. A baudrate change
+/// is passed on to the control point after it was answered. Fails only when the link closed.
+pub async fn forward<T: TelegramTransport>(
+    client: &mut Client<T>,
+    label: &str,
+    telegram: &Telegram,
+    registry: &Registry,
+    control_timeout: Duration,
+) -> Result<Option<Telegram>> {
+    println!(
+        "{} {}: {}",
+        label,
+        "Request".blue(),
+        registry.display(telegram)
+    );
+    let result = client.request(telegram).await;
+    for stray in client.strays() {
+        println!(
+            "{} {}: {}",
+            label,
+            "Stray".yellow(),
+            registry.display(stray)
+        );
+    }
+    let response = match result {
+        Ok(r) => {
+            println!("{} {}: {}", label, "Response".green(), registry.display(&r));
+            Some(r)
+        }
+        Err(RequestError::Closed) => bail!("link to the device closed"),
+        Err(e) => {
+            println!("{}     {}{}", label, "Error: ".red(), e);
+            None
+        }
+    };
+
+    if telegram.command == Command::Write && telegram.subcommand == CHANGE_BAUDRATE {
+        match <[u8; 4]>::try_from(telegram.data.as_slice()) {
+            Ok(data) => {
+                let rate = u32::from_be_bytes(data);
+                println!("{} Baudrate change to {}", label, rate);
+                match Baudrate::try_from(rate) {
+                    Ok(baudrate) => match control_point::assign_baudrate(
+                        client.transport_mut(),
+                        baudrate,
+                        control_timeout,
+                    )
+                    .await
+                    {
+                        Ok(()) => println!("{} new baudrate successful", label),
+                        Err(e) => eprintln!("{} {}", label, e),
+                    },
+                    Err(e) => eprintln!("{} {}", label, e),
+                }
+            }
+            Err(_) => println!("{} Baudrate change without a 4 byte baudrate", label),
+        }
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::{
+        prefab::greet,
+        simulator::{Simulator, SimulatorConfig},
+        telegram::{format_hex, parse_hex},
+        transport::LoopbackTransport,
+    };
+    use crate::protocol::{GREET, TESTBENCH};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+    async fn request(addr: SocketAddr, subcommand: u8) -> Telegram {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let telegram = Telegram {
+            subcommand,
+            data: vec![subcommand],
+            ..greet()
+        };
+        let bytes = telegram.to_bytes().unwrap();
+        stream
+            .write_all(&(bytes.len() as u16).to_be_bytes())
+            .await
+            .unwrap();
+        stream.write_all(&bytes).await.unwrap();
+        let mut response = vec![0u8; 256];
+        let n = stream.read(&mut response).await.unwrap();
+        Telegram::from_bytes(&response[..n]).unwrap()
+    }
+
+    async fn hex_request(addr: SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"0E 92 FF FF FF FF 04 01 CC B1 21\n")
+            .await
+            .unwrap();
+        let mut response = vec![0u8; 256];
+        let n = stream.read(&mut response).await.unwrap();
+        String::from_utf8(response[..n].to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_clients() {
+        let config = SimulatorConfig {
+            latency: Duration::from_millis(5),
+            ..Default::default()
+        };
+        let mut client = Client::new(Simulator::new(config).transport(), Default::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel(QUEUE_DEPTH);
+        let registry = Registry::builtin();
+        // The responses are raw, like before the framing was selectable.
+        let framing = StreamFraming {
+            request: Framing::LengthPrefixed,
+            response: Framing::Raw,
+        };
+
+        let connected = Clients::default();
+
+        let server = async {
+            tokio::select! {
+                r = accept(listener, tx, framing, connected.clone()) => r,
+                r = serve_link(&mut client, rx, &registry, Duration::from_millis(100), &connected) => r,
+            }
+        };
+        let clients = async {
+            futures::join!(
+                request(addr, GREET),
+                request(addr, TESTBENCH),
+                request(addr, GREET)
+            )
+        };
+        let (greet, testbench, again) = tokio::select! {
+            r = server => panic!("server stopped: {:?}", r.err()),
+            r = clients => r,
+        };
+        assert_eq!(greet.data, b"BlueSmile");
+        assert_eq!(testbench.data, vec![TESTBENCH]);
+        assert_eq!(again.data, b"BlueSmile");
+        assert_eq!(client.stats().strays, 0);
+    }
+
+    #[tokio::test]
+    async fn test_hex_framing() {
+        let mut client = Client::new(
+            Simulator::new(SimulatorConfig::default()).transport(),
+            Default::default(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel(QUEUE_DEPTH);
+        let registry = Registry::builtin();
+        let framing = StreamFraming {
+            request: Framing::Hex,
+            response: Framing::Hex,
+        };
+
+        let connected = Clients::default();
+
+        let response = tokio::select! {
+            r = accept(listener, tx, framing, connected.clone()) => {
+                panic!("server stopped: {:?}", r.err())
+            }
+            r = serve_link(&mut client, rx, &registry, Duration::from_millis(100), &connected) => {
+                panic!("link stopped: {:?}", r.err())
+            }
+            r = hex_request(addr) => r,
+        };
+        assert_eq!(
+            response,
+            "0E 92 00 BC 61 4E 0D 01 CC 42 6C 75 65 53 6D 69 6C 65 60 8B\n"
+        );
+    }
+
+    type Lines = tokio::io::Lines<tokio::io::BufReader<tokio::net::tcp::OwnedReadHalf>>;
+
+    const TRIGGER: u8 = 0x50;
+    const EVENT: u8 = 0x60;
+
+    fn telegram(subcommand: u8) -> Telegram {
+        Telegram {
+            serial_number: 12345678,
+            subcommand,
+            ..greet()
+        }
+    }
+
+    /// Connects with hex framing and sends a request, returns the client once it was answered.
+    async fn hex_client(addr: SocketAddr, subcommand: u8) -> (Lines, OwnedWriteHalf) {
+        let (reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        writer
+            .write_all(&Framing::Hex.encode(&telegram(subcommand)).unwrap())
+            .await
+            .unwrap();
+        let response = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(
+            response,
+            format_hex(&telegram(subcommand).to_bytes().unwrap())
+        );
+        (lines, writer)
+    }
+
+    /// The next telegram pushed to a client, `None` when nothing arrives shortly.
+    async fn pushed(lines: &mut Lines) -> Option<Telegram> {
+        let line = tokio::time::timeout(Duration::from_millis(100), lines.next_line())
+            .await
+            .ok()?
+            .unwrap()?;
+        Some(Telegram::from_bytes(&parse_hex(&line).unwrap()).unwrap())
+    }
+
+    /// What two clients receive when the device sends an event after a request of the second.
+    async fn route_event(routing: Routing) -> (Option<Telegram>, Option<Telegram>) {
+        let (transport, mut device) = LoopbackTransport::pair();
+        let device = async move {
+            while let Some(request) = device.recv().await {
+                device.send(&request).unwrap();
+                if request.subcommand == TRIGGER {
+                    device.send(&telegram(EVENT)).unwrap();
+                }
+            }
+        };
+        let mut client = Client::new(transport, Default::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel(QUEUE_DEPTH);
+        let registry = Registry::builtin();
+        let framing = StreamFraming {
+            request: Framing::Hex,
+            response: Framing::Hex,
+        };
+        let connected = Clients::new(routing);
+
+        let clients = async {
+            let (mut first, _first) = hex_client(addr, GREET).await;
+            let (mut second, _second) = hex_client(addr, TRIGGER).await;
+            (pushed(&mut first).await, pushed(&mut second).await)
+        };
+        tokio::select! {
+            _ = device => panic!("device stopped"),
+            r = accept(listener, tx, framing, connected.clone()) => {
+                panic!("server stopped: {:?}", r.err())
+            }
+            r = serve_link(&mut client, rx, &registry, Duration::from_millis(100), &connected) => {
+                panic!("link stopped: {:?}", r.err())
+            }
+            r = clients => r,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_notifications() {
+        let event = Some(telegram(EVENT));
+        assert_eq!(
+            route_event(Routing::All).await,
+            (event.clone(), event.clone())
+        );
+        assert_eq!(route_event(Routing::Owner).await, (None, event));
+        assert_eq!(route_event(Routing::None).await, (None, None));
+        assert!("owner".parse::<Routing>() == Ok(Routing::Owner));
+    }
+}
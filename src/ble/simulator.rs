@@ -0,0 +1,346 @@
+use std::{collections::BTreeMap, fs, os::unix::fs::FileTypeExt, path::Path};
+
+use anyhow::Result;
+use crc::{Crc, CRC_16_MODBUS};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UnixListener},
+    time::{sleep, Duration},
+};
+
+use crate::ble::{
+    framing::{FrameReader, StreamFraming},
+    register_map::READ_REGISTER,
+    telegram::{Command, Telegram, ANY_DEVICE_TYPE, ANY_SERIAL_NUMBER, MAX_DATA_LEN},
+    transport::{LoopbackDevice, LoopbackRequest, LoopbackTransport},
+};
+use crate::protocol::{
+    Baudrate, CommandType, ControlResponse, BIG_RESPONSE, CHANGE_BAUDRATE, GREET, TESTBENCH,
+};
+
+const CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_MODBUS);
+
+#[derive(Debug, Clone)]
+pub struct SimulatorConfig {
+    pub device_type: u16,
+    pub serial_number: u32,
+    /// Delay before every response.
+    pub latency: Duration,
+    /// Probability that a request is not answered.
+    pub drop_rate: f64,
+    /// Probability that the CRC of a response is corrupted.
+    pub corrupt_rate: f64,
+    /// Seed for drops and corruption, so failing runs can be reproduced.
+    pub seed: Option<u64>,
+    /// Register values answered to register reads, unknown registers read as 0.
+    pub registers: BTreeMap<u16, u16>,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        SimulatorConfig {
+            device_type: 3730,
+            serial_number: 12345678,
+            latency: Duration::ZERO,
+            drop_rate: 0.0,
+            corrupt_rate: 0.0,
+            seed: None,
+            registers: BTreeMap::new(),
+        }
+    }
+}
+
+/// Emulates a BlueSmile module: the testbench subcommands and the control point.
+pub struct Simulator {
+    config: SimulatorConfig,
+    rng: StdRng,
+    pub passkey: u32,
+    pub baudrate: u32,
+}
+
+impl Simulator {
+    pub fn new(config: SimulatorConfig) -> Self {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
+        Simulator {
+            config,
+            rng,
+            passkey: 0,
+            baudrate: 9600,
+        }
+    }
+
+    fn is_addressed(&self, request: &Telegram) -> bool {
+        (request.device_type == self.config.device_type || request.device_type == ANY_DEVICE_TYPE)
+            && (request.serial_number == self.config.serial_number
+                || request.serial_number == ANY_SERIAL_NUMBER)
+    }
+
+    /// The response of a well behaving module, `None` for requests it doesn't answer.
+    pub fn respond(&self, request: &Telegram) -> Option<Telegram> {
+        if !self.is_addressed(request) {
+            return None;
+        }
+        let data = match (request.command, request.subcommand) {
+            (Command::Read, TESTBENCH) => request.data.clone(),
+            (Command::Read, GREET) => b"BlueSmile".to_vec(),
+            (Command::Read, BIG_RESPONSE) => (0..MAX_DATA_LEN).map(|i| i as u8).collect(),
+            (Command::Read, READ_REGISTER) => {
+                let address = u16::from_be_bytes(request.data.get(..2)?.try_into().ok()?);
+                let value = self.config.registers.get(&address).copied().unwrap_or(0);
+                [address.to_be_bytes(), value.to_be_bytes()].concat()
+            }
+            (Command::Write, CHANGE_BAUDRATE) => request.data.clone(),
+            _ => return None,
+        };
+        Some(Telegram {
+            device_type: self.config.device_type,
+            serial_number: self.config.serial_number,
+            command: request.command,
+            subcommand: request.subcommand,
+            data,
+        })
+    }
+
+    /// Applies a [`ControlCommand`](crate::protocol::ControlCommand) and returns the echoed
+    /// value, little endian like the module does. Commands with a bad CRC are ignored.
+    pub fn respond_control(&mut self, command: &[u8]) -> Option<Vec<u8>> {
+        let (body, crc) = command.split_at_checked(5)?;
+        if crc != CRC.checksum(body).to_le_bytes() {
+            return None;
+        }
+        let value = u32::from_le_bytes(body[1..5].try_into().ok()?);
+        let echo = match body[0] {
+            b if b == CommandType::PASSKEY.serialize() => {
+                self.passkey = value;
+                value
+            }
+            b if b == CommandType::BAUDRATE.serialize() => match Baudrate::try_from(value) {
+                Ok(baudrate) => {
+                    self.baudrate = value;
+                    baudrate.divisor()
+                }
+                Err(_) => 0,
+            },
+            _ => return None,
+        };
+        Some(ControlResponse(echo).serialize())
+    }
+
+    /// The bytes sent back for a request, after the configured drops and corruption.
+    pub fn handle(&mut self, request: &Telegram) -> Option<Vec<u8>> {
+        let response = self.respond(request)?.to_bytes().ok()?;
+        self.impair(response)
+    }
+
+    pub fn handle_control(&mut self, command: &[u8]) -> Option<Vec<u8>> {
+        let response = self.respond_control(command)?;
+        self.impair(response)
+    }
+
+    fn impair(&mut self, mut response: Vec<u8>) -> Option<Vec<u8>> {
+        if self.rng.random::<f64>() < self.config.drop_rate {
+            return None;
+        }
+        if self.rng.random::<f64>() < self.config.corrupt_rate {
+            let last = response.len() - 1;
+            response[last] ^= 0xFF;
+        }
+        Some(response)
+    }
+
+    /// Answers the requests of a [`LoopbackTransport`] until it is closed.
+    pub async fn serve(mut self, mut device: LoopbackDevice) {
+        while let Some(request) = device.recv_any().await {
+            let (response, control) = match &request {
+                LoopbackRequest::Telegram(t) => (self.handle(t), false),
+                LoopbackRequest::Control(c) => (self.handle_control(c), true),
+            };
+            let Some(response) = response else {
+                continue;
+            };
+            sleep(self.config.latency).await;
+            let sent = if control {
+                device.send_control(response)
+            } else {
+                device.send_raw(response)
+            };
+            if sent.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// A transport connected to a simulator running on the current runtime.
+    pub fn transport(self) -> LoopbackTransport {
+        let (transport, device) = LoopbackTransport::pair();
+        tokio::spawn(self.serve(device));
+        transport
+    }
+
+    /// Answers telegrams on a byte stream in the framing of `pass-through`. Corrupted responses
+    /// can't be sent as JSON and are dropped instead.
+    pub async fn serve_stream<S>(&mut self, stream: &mut S, framing: StreamFraming) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = FrameReader::new(reader, framing.request);
+        while let Some(request) = reader.read().await? {
+            let request = match request {
+                Ok(t) => t,
+                Err(e) => {
+                    println!("   Error in request {}", e);
+                    continue;
+                }
+            };
+            let Some(response) = self.handle(&request) else {
+                continue;
+            };
+            if let Ok(bytes) = framing.response.frame(&response) {
+                sleep(self.config.latency).await;
+                writer.write_all(&bytes).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Serves TCP clients one after another.
+    pub async fn listen_tcp(mut self, addr: &str, framing: StreamFraming) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        println!("Simulator listening on {}", listener.local_addr()?);
+        loop {
+            let (mut stream, peer) = listener.accept().await?;
+            println!("Client connected {}", peer);
+            if let Err(e) = self.serve_stream(&mut stream, framing).await {
+                println!("Client error {}", e);
+            }
+            println!("Client disconnected");
+        }
+    }
+
+    /// Serves Unix socket clients one after another.
+    pub async fn listen_unix(mut self, path: &Path, framing: StreamFraming) -> Result<()> {
+        // Replace the socket of an earlier run, but never a regular file.
+        if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        println!("Simulator listening on {}", path.display());
+        loop {
+            let (mut stream, _) = listener.accept().await?;
+            println!("Client connected");
+            if let Err(e) = self.serve_stream(&mut stream, framing).await {
+                println!("Client error {}", e);
+            }
+            println!("Client disconnected");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::{
+        control_point, prefab::greet, telegram::TelegramError, transport::TelegramTransport,
+    };
+    use crate::protocol::ControlCommand;
+
+    fn request(subcommand: u8, data: Vec<u8>) -> Telegram {
+        Telegram {
+            subcommand,
+            data,
+            ..greet()
+        }
+    }
+
+    #[test]
+    fn test_responses() {
+        let mut config = SimulatorConfig::default();
+        config.registers.insert(0x0031, 0x00D7);
+        let simulator = Simulator::new(config);
+
+        let greet = simulator.respond(&request(GREET, vec![])).unwrap();
+        assert_eq!(greet.serial_number, 12345678);
+        assert_eq!(greet.data, b"BlueSmile");
+
+        let big = simulator.respond(&request(BIG_RESPONSE, vec![])).unwrap();
+        assert_eq!(big.to_bytes().unwrap().len(), 255);
+
+        let register = simulator
+            .respond(&request(READ_REGISTER, vec![0x00, 0x31]))
+            .unwrap();
+        assert_eq!(register.data, vec![0x00, 0x31, 0x00, 0xD7]);
+
+        let mut other = request(GREET, vec![]);
+        other.device_type = 3793;
+        assert_eq!(simulator.respond(&other), None);
+        assert_eq!(simulator.respond(&request(99, vec![])), None);
+    }
+
+    #[test]
+    fn test_control_point() {
+        let mut simulator = Simulator::new(SimulatorConfig::default());
+        let baudrate = ControlCommand::new(CommandType::BAUDRATE, 9600u32.to_le_bytes());
+        assert_eq!(
+            simulator.respond_control(&baudrate.serialize()),
+            Some(vec![0x03, 0x68, 0x00, 0x00])
+        );
+
+        let passkey = ControlCommand::new(CommandType::PASSKEY, 123456u32.to_le_bytes());
+        let mut corrupted = passkey.serialize();
+        corrupted[6] ^= 0xFF;
+        assert_eq!(simulator.respond_control(&corrupted), None);
+        assert_eq!(
+            simulator.respond_control(&passkey.serialize()),
+            Some(123456u32.to_le_bytes().to_vec())
+        );
+        assert_eq!(simulator.passkey, 123456);
+    }
+
+    #[tokio::test]
+    async fn test_transport() {
+        let mut transport = Simulator::new(SimulatorConfig::default()).transport();
+        let timeout = Duration::from_millis(100);
+
+        transport.send(&request(GREET, vec![])).await.unwrap();
+        assert_eq!(transport.recv().await.unwrap().unwrap().data, b"BlueSmile");
+
+        control_point::assign_baudrate(
+            &mut transport,
+            Baudrate::try_from(115200).unwrap(),
+            timeout,
+        )
+        .await
+        .unwrap();
+        control_point::assign_passkey(&mut transport, 654321, timeout)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_impairments() {
+        let config = SimulatorConfig {
+            corrupt_rate: 1.0,
+            seed: Some(1),
+            ..Default::default()
+        };
+        let mut transport = Simulator::new(config).transport();
+        transport.send(&request(GREET, vec![])).await.unwrap();
+        assert!(matches!(
+            transport.recv().await,
+            Some(Err(TelegramError::BadChecksum { .. }))
+        ));
+
+        let config = SimulatorConfig {
+            drop_rate: 1.0,
+            seed: Some(1),
+            ..Default::default()
+        };
+        let mut simulator = Simulator::new(config);
+        assert_eq!(simulator.handle(&request(GREET, vec![])), None);
+    }
+}
@@ -0,0 +1,256 @@
+use std::fmt::Display;
+
+use serde::Serialize;
+use tokio::time::Duration;
+
+use crate::ble::{
+    client::{ClientStats, RequestError},
+    telegram_sequence::{Outcome, Step},
+};
+
+/// Round-trip latencies in milliseconds.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LatencySummary {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+}
+
+impl LatencySummary {
+    /// `None` when there are no latencies.
+    pub fn new(latencies: &[Duration]) -> Option<Self> {
+        let mut sorted: Vec<f64> = latencies.iter().map(|d| d.as_secs_f64() * 1000.0).collect();
+        sorted.sort_by(f64::total_cmp);
+        let count = sorted.len();
+        Some(LatencySummary {
+            min: *sorted.first()?,
+            max: *sorted.last()?,
+            mean: sorted.iter().sum::<f64>() / count as f64,
+            p50: percentile(&sorted, 50.0),
+            p95: percentile(&sorted, 95.0),
+            p99: percentile(&sorted, 99.0),
+        })
+    }
+}
+
+/// Nearest-rank percentile of sorted, non-empty values.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Summary of a run, in the schema of the json output.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RunSummary {
+    pub requests: usize,
+    pub responses: usize,
+    pub timeouts: usize,
+    /// Requests that failed because only invalid frames arrived.
+    pub failed_frames: usize,
+    pub crc_failures: usize,
+    /// Requests with expectations, and those whose response didn't meet them.
+    pub checks: usize,
+    pub check_failures: usize,
+    /// Requests without expectations that got no valid response.
+    pub errors: usize,
+    /// Steps with expectations that weren't sent because the transport closed or failed.
+    pub skipped_checks: usize,
+    pub retries: usize,
+    pub strays: usize,
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+    pub duration_s: f64,
+    pub bytes_per_second: f64,
+    /// Latencies of the answered requests, absent when none was answered.
+    pub latency_ms: Option<LatencySummary>,
+}
+
+impl RunSummary {
+    /// Summarizes the `outcomes` of `steps`, steps past the last outcome weren't sent.
+    pub fn new(
+        steps: &[Step],
+        outcomes: &[Outcome],
+        client: ClientStats,
+        duration: Duration,
+    ) -> Self {
+        let count = |f: fn(&RequestError) -> bool| {
+            outcomes
+                .iter()
+                .filter(|o| o.result.as_ref().is_err_and(f))
+                .count()
+        };
+        let latencies: Vec<Duration> = outcomes
+            .iter()
+            .filter(|o| o.result.is_ok())
+            .map(|o| o.elapsed)
+            .collect();
+        let duration_s = duration.as_secs_f64();
+        let bytes = (client.bytes_sent + client.bytes_received) as f64;
+
+        RunSummary {
+            requests: outcomes.len(),
+            responses: latencies.len(),
+            timeouts: count(|e| matches!(e, RequestError::Timeout { .. })),
+            failed_frames: count(|e| matches!(e, RequestError::BadFrame { .. })),
+            crc_failures: client.bad_checksums,
+            checks: outcomes.iter().filter(|o| o.check.is_some()).count(),
+            check_failures: outcomes
+                .iter()
+                .filter(|o| matches!(o.check, Some(Err(_))))
+                .count(),
+            errors: outcomes
+                .iter()
+                .filter(|o| o.check.is_none() && o.result.is_err())
+                .count(),
+            skipped_checks: steps
+                .iter()
+                .skip(outcomes.len())
+                .filter(|s| !s.expect.is_empty())
+                .count(),
+            retries: client.retries,
+            strays: client.strays,
+            bytes_sent: client.bytes_sent,
+            bytes_received: client.bytes_received,
+            duration_s,
+            bytes_per_second: if duration_s > 0.0 {
+                bytes / duration_s
+            } else {
+                0.0
+            },
+            latency_ms: LatencySummary::new(&latencies),
+        }
+    }
+
+    /// Steps that make the run fail: failed checks, failed requests and skipped checks.
+    pub fn failures(&self) -> usize {
+        self.check_failures + self.errors + self.skipped_checks
+    }
+}
+
+impl Display for RunSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rows: [(&str, String); 15] = [
+            ("requests", self.requests.to_string()),
+            ("responses", self.responses.to_string()),
+            ("timeouts", self.timeouts.to_string()),
+            ("failed frames", self.failed_frames.to_string()),
+            ("crc failures", self.crc_failures.to_string()),
+            ("checks", self.checks.to_string()),
+            ("check failures", self.check_failures.to_string()),
+            ("errors", self.errors.to_string()),
+            ("skipped checks", self.skipped_checks.to_string()),
+            ("retries", self.retries.to_string()),
+            ("strays", self.strays.to_string()),
+            ("bytes sent", self.bytes_sent.to_string()),
+            ("bytes received", self.bytes_received.to_string()),
+            ("duration", format!("{:.3} s", self.duration_s)),
+            (
+                "throughput",
+                format!("{:.1} bytes/s", self.bytes_per_second),
+            ),
+        ];
+        for (name, value) in rows {
+            writeln!(f, "  {:<16}{:>14}", name, value)?;
+        }
+
+        let Some(l) = &self.latency_ms else {
+            return write!(f, "  {:<16}{:>14}", "latency", "-");
+        };
+        writeln!(
+            f,
+            "\n  {:<16}{:>9}{:>9}{:>9}{:>9}{:>9}{:>9}",
+            "latency (ms)", "min", "max", "mean", "p50", "p95", "p99"
+        )?;
+        write!(
+            f,
+            "  {:<16}{:>9.2}{:>9.2}{:>9.2}{:>9.2}{:>9.2}{:>9.2}",
+            "", l.min, l.max, l.mean, l.p50, l.p95, l.p99
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::{
+        client::{Client, RequestOptions},
+        expect::Expectation,
+        prefab::greet,
+        telegram::Telegram,
+        telegram_sequence::EventSequence,
+        transport::LoopbackTransport,
+    };
+
+    fn outcome(ms: u64, result: Result<Telegram, RequestError>) -> Outcome {
+        Outcome {
+            result,
+            elapsed: Duration::from_millis(ms),
+            check: None,
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let response = Telegram {
+            serial_number: 12345678,
+            ..greet()
+        };
+        let mut outcomes: Vec<Outcome> = (1..=100)
+            .map(|ms| outcome(ms, Ok(response.clone())))
+            .collect();
+        outcomes.push(outcome(1500, Err(RequestError::Timeout { attempts: 1 })));
+        outcomes[0].check = Some(Ok(()));
+        outcomes[100].check = Some(Err("no response after 1 attempt(s)".to_string()));
+
+        let client = ClientStats {
+            bytes_sent: 1111,
+            bytes_received: 889,
+            ..Default::default()
+        };
+        let summary = RunSummary::new(&[], &outcomes, client, Duration::from_secs(2));
+
+        assert_eq!(summary.requests, 101);
+        assert_eq!(summary.responses, 100);
+        assert_eq!(summary.timeouts, 1);
+        assert_eq!((summary.checks, summary.check_failures), (2, 1));
+        assert_eq!(summary.bytes_per_second, 1000.0);
+        let latency = summary.latency_ms.unwrap();
+        assert_eq!((latency.min, latency.max), (1.0, 100.0));
+        assert_eq!(latency.mean, 50.5);
+        assert_eq!((latency.p50, latency.p95, latency.p99), (50.0, 95.0, 99.0));
+    }
+
+    #[test]
+    fn test_no_responses() {
+        let outcomes = vec![outcome(1500, Err(RequestError::Timeout { attempts: 1 }))];
+        let summary = RunSummary::new(&[], &outcomes, ClientStats::default(), Duration::ZERO);
+        assert_eq!(summary.latency_ms, None);
+        assert_eq!(summary.bytes_per_second, 0.0);
+        assert_eq!((summary.errors, summary.failures()), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_closed_before_check() {
+        let mut sequence = EventSequence::new(vec![greet(); 3], Duration::ZERO);
+        sequence.steps[2].expect = vec![Expectation::Subcommand(204)];
+        let (transport, mut device) = LoopbackTransport::pair();
+
+        // The device answers the first request, then the link closes.
+        let device = async {
+            let t = device.recv().await.unwrap();
+            device.send(&t).unwrap();
+            device.close();
+        };
+        let mut client = Client::new(transport, RequestOptions::default());
+        let (outcomes, ()) = tokio::join!(sequence.run(&mut client, |_, _, _| {}), device);
+
+        let summary = RunSummary::new(&sequence.steps, &outcomes, client.stats(), Duration::ZERO);
+        assert_eq!(summary.requests, 2);
+        assert_eq!((summary.checks, summary.check_failures), (0, 0));
+        assert_eq!((summary.errors, summary.skipped_checks), (1, 1));
+        assert_eq!(summary.failures(), 2);
+    }
+}
@@ -0,0 +1,348 @@
+use std::{fmt::Display, future::Future, pin::Pin, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use tokio::{
+    sync::watch,
+    time::{sleep, Duration},
+};
+
+use crate::ble::{
+    telegram::{Telegram, TelegramError},
+    transport::TelegramTransport,
+};
+
+/// The state of a supervised link, see [`SupervisedTransport::link_state`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    Connected,
+    /// The link dropped and is reestablished, `attempt` counts from 1.
+    Reconnecting {
+        attempt: u32,
+    },
+    /// Every reconnection attempt failed, the transport stays closed.
+    Lost,
+}
+
+impl Display for LinkState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Connected => f.write_str("connected"),
+            Self::Reconnecting { attempt } => write!(f, "reconnecting (attempt {})", attempt),
+            Self::Lost => f.write_str("lost"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectPolicy {
+    /// Attempts to reestablish a dropped link, 0 gives up right away.
+    pub attempts: u32,
+    /// Delay before the first attempt, doubled for every following attempt.
+    pub backoff: Duration,
+    /// Upper bound of the delay between attempts.
+    pub max_backoff: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            attempts: 10,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// The delay before reconnection attempt `attempt`, counting from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u32 << attempt.saturating_sub(1).min(16);
+        self.backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+/// A connection to a device that can drop and be reestablished.
+#[allow(async_fn_in_trait)]
+pub trait Link {
+    type Transport: TelegramTransport;
+
+    /// Resolves once the device reports that it disconnected.
+    async fn disconnected(&mut self);
+
+    async fn is_connected(&mut self) -> bool;
+
+    /// Connects again and returns a transport with the notifications subscribed anew.
+    async fn connect(&mut self) -> Result<Self::Transport>;
+}
+
+type Reconnect<L> = Pin<Box<dyn Future<Output = Option<(L, <L as Link>::Transport)>>>>;
+
+enum Stage<L: Link> {
+    Up {
+        link: L,
+        transport: L::Transport,
+    },
+    /// Reconnection runs in a boxed future, so a caller that gives up waiting, e.g. on a request
+    /// timeout, doesn't restart it.
+    Reconnecting(Reconnect<L>),
+    Down,
+}
+
+/// A transport that survives its link dropping: a disconnect is detected from the device
+/// events or the end of the notifications, and the link is reconnected with backoff. Sending
+/// waits for the reconnection, the telegrams received after it continue the stream. Link state
+/// changes are logged to stderr.
+pub struct SupervisedTransport<L: Link> {
+    stage: Stage<L>,
+    policy: ReconnectPolicy,
+    state: Arc<watch::Sender<LinkState>>,
+    /// Whether the notifications are kept, also by the transports of later connections.
+    keep: bool,
+    /// Notifications of transports that were dropped before they were taken.
+    kept: Vec<Vec<u8>>,
+}
+
+impl<L: Link + 'static> SupervisedTransport<L> {
+    /// Supervises `link`, which is connected and open as `transport`.
+    pub fn new(link: L, transport: L::Transport, policy: ReconnectPolicy) -> Self {
+        SupervisedTransport {
+            stage: Stage::Up { link, transport },
+            policy,
+            state: Arc::new(watch::channel(LinkState::Connected).0),
+            keep: false,
+            kept: Vec::new(),
+        }
+    }
+
+    /// Follows the state of the link.
+    pub fn link_state(&self) -> watch::Receiver<LinkState> {
+        self.state.subscribe()
+    }
+
+    /// The open link, once it is reestablished after a drop. `None` when it's lost or closed.
+    async fn up(&mut self) -> Option<(&mut L, &mut L::Transport)> {
+        if let Stage::Reconnecting(reconnect) = &mut self.stage {
+            self.stage = match reconnect.await {
+                Some((link, mut transport)) => {
+                    if self.keep {
+                        transport.keep_notifications();
+                    }
+                    Stage::Up { link, transport }
+                }
+                None => Stage::Down,
+            };
+        }
+        match &mut self.stage {
+            Stage::Up { link, transport } => Some((link, transport)),
+            _ => None,
+        }
+    }
+
+    fn dropped(&mut self) {
+        if let Stage::Up {
+            link,
+            mut transport,
+        } = std::mem::replace(&mut self.stage, Stage::Down)
+        {
+            self.kept.extend(transport.take_notifications());
+            self.stage =
+                Stage::Reconnecting(Box::pin(reconnect(link, self.policy, self.state.clone())));
+        }
+    }
+
+    /// Starts reconnecting when a failed write was caused by the link dropping.
+    async fn failed<T>(&mut self, result: Result<T>) -> Result<T> {
+        if result.is_err() {
+            if let Stage::Up { link, .. } = &mut self.stage {
+                if !link.is_connected().await {
+                    self.dropped();
+                }
+            }
+        }
+        result
+    }
+
+    fn lost() -> anyhow::Error {
+        anyhow!("link to the device is lost")
+    }
+}
+
+fn report(state: &watch::Sender<LinkState>, link_state: LinkState) {
+    eprintln!("Link {}", link_state);
+    state.send_replace(link_state);
+}
+
+async fn reconnect<L: Link>(
+    mut link: L,
+    policy: ReconnectPolicy,
+    state: Arc<watch::Sender<LinkState>>,
+) -> Option<(L, L::Transport)> {
+    for attempt in 1..=policy.attempts {
+        report(&state, LinkState::Reconnecting { attempt });
+        sleep(policy.delay(attempt)).await;
+        match link.connect().await {
+            Ok(transport) => {
+                report(&state, LinkState::Connected);
+                return Some((link, transport));
+            }
+            Err(e) => eprintln!("Reconnecting failed: {}", e),
+        }
+    }
+    report(&state, LinkState::Lost);
+    None
+}
+
+impl<L: Link + 'static> TelegramTransport for SupervisedTransport<L> {
+    async fn send(&mut self, telegram: &Telegram) -> Result<()> {
+        let (_, transport) = self.up().await.ok_or_else(Self::lost)?;
+        let result = transport.send(telegram).await;
+        self.failed(result).await
+    }
+
+    async fn recv(&mut self) -> Option<Result<Telegram, TelegramError>> {
+        loop {
+            let (link, transport) = self.up().await?;
+            tokio::select! {
+                received = transport.recv() => if received.is_some() {
+                    return received;
+                },
+                _ = link.disconnected() => {}
+            }
+            self.dropped();
+        }
+    }
+
+    async fn send_control(&mut self, command: &[u8]) -> Result<()> {
+        let (_, transport) = self.up().await.ok_or_else(Self::lost)?;
+        let result = transport.send_control(command).await;
+        self.failed(result).await
+    }
+
+    async fn recv_control(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let (link, transport) = self.up().await?;
+            tokio::select! {
+                received = transport.recv_control() => if received.is_some() {
+                    return received;
+                },
+                _ = link.disconnected() => {}
+            }
+            self.dropped();
+        }
+    }
+
+    /// Closes the transport and stops reconnecting.
+    async fn close(&mut self) -> Result<()> {
+        match std::mem::replace(&mut self.stage, Stage::Down) {
+            Stage::Up { mut transport, .. } => transport.close().await,
+            _ => Ok(()),
+        }
+    }
+
+    fn keep_notifications(&mut self) -> bool {
+        self.keep = true;
+        match &mut self.stage {
+            Stage::Up { transport, .. } => transport.keep_notifications(),
+            _ => false,
+        }
+    }
+
+    fn take_notifications(&mut self) -> Vec<Vec<u8>> {
+        let mut kept = std::mem::take(&mut self.kept);
+        if let Stage::Up { transport, .. } = &mut self.stage {
+            kept.extend(transport.take_notifications());
+        }
+        kept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::{
+        prefab::greet,
+        transport::{LoopbackDevice, LoopbackTransport},
+    };
+    use std::{cell::RefCell, rc::Rc};
+    use tokio::sync::Notify;
+
+    /// Hands out loopback transports, the devices end up in `devices`.
+    struct TestLink {
+        devices: Rc<RefCell<Vec<LoopbackDevice>>>,
+        /// Connection attempts that fail before one succeeds.
+        failures: Rc<RefCell<u32>>,
+        drop_event: Rc<Notify>,
+        connects: Rc<RefCell<u32>>,
+    }
+
+    impl Link for TestLink {
+        type Transport = LoopbackTransport;
+
+        async fn disconnected(&mut self) {
+            self.drop_event.notified().await
+        }
+
+        async fn is_connected(&mut self) -> bool {
+            false
+        }
+
+        async fn connect(&mut self) -> Result<LoopbackTransport> {
+            *self.connects.borrow_mut() += 1;
+            let mut failures = self.failures.borrow_mut();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(anyhow!("device not available"));
+            }
+            let (transport, device) = LoopbackTransport::pair();
+            device.send(&greet()).unwrap();
+            self.devices.borrow_mut().push(device);
+            Ok(transport)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let policy = ReconnectPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(1));
+        assert_eq!(policy.delay(3), Duration::from_millis(2));
+
+        let devices = Rc::new(RefCell::new(Vec::new()));
+        let failures = Rc::new(RefCell::new(0));
+        let drop_event = Rc::new(Notify::new());
+        let connects = Rc::new(RefCell::new(0));
+        let link = TestLink {
+            devices: devices.clone(),
+            failures: failures.clone(),
+            drop_event: drop_event.clone(),
+            connects: connects.clone(),
+        };
+        let (transport, mut device) = LoopbackTransport::pair();
+        let mut supervised = SupervisedTransport::new(link, transport, policy);
+        let state = supervised.link_state();
+
+        supervised.send(&greet()).await.unwrap();
+        assert_eq!(device.recv().await, Some(greet()));
+
+        // The device reports the disconnect, the first attempt fails.
+        *failures.borrow_mut() = 1;
+        drop_event.notify_one();
+        assert_eq!(supervised.recv().await, Some(Ok(greet())));
+        assert_eq!(*connects.borrow(), 2);
+        assert_eq!(*state.borrow(), LinkState::Connected);
+        supervised.send(&greet()).await.unwrap();
+        let mut device = devices.borrow_mut().pop().unwrap();
+        assert_eq!(device.recv().await, Some(greet()));
+
+        // The notifications end without an event, every attempt fails.
+        *failures.borrow_mut() = 3;
+        drop(device);
+        assert_eq!(supervised.recv().await, None);
+        assert_eq!(*connects.borrow(), 5);
+        assert_eq!(*state.borrow(), LinkState::Lost);
+        assert!(supervised.send(&greet()).await.is_err());
+    }
+}
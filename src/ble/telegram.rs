@@ -1,9 +1,71 @@
-use std::fmt::Display;
+use std::{error::Error, fmt::Display, str::FromStr};
 
 use crc::{Crc, CRC_16_MODBUS};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, PartialEq, Clone)]
+/// Smallest possible frame: header, length, command, subcommand and CRC without any data.
+pub const MIN_FRAME_LEN: usize = 11;
+/// Largest frame the module accepts.
+pub const MAX_FRAME_LEN: usize = 255;
+/// Largest payload that still fits in [`MAX_FRAME_LEN`].
+pub const MAX_DATA_LEN: usize = MAX_FRAME_LEN - MIN_FRAME_LEN;
+/// Bytes before the length byte's counted region: device type, serial number and the length byte itself.
+pub const HEADER_LEN: usize = 7;
+/// Device type that addresses every device type.
+pub const ANY_DEVICE_TYPE: u16 = 0xFFFF;
+/// Serial number that addresses every device.
+pub const ANY_SERIAL_NUMBER: u32 = 0xFFFFFFFF;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TelegramError {
+    /// The frame is shorter than [`MIN_FRAME_LEN`].
+    TooShort(usize),
+    /// The frame (or the frame that would be produced) exceeds [`MAX_FRAME_LEN`].
+    TooLong(usize),
+    /// The length byte does not match the number of bytes that follow it.
+    LengthMismatch {
+        declared: u8,
+        actual: usize,
+    },
+    /// The CRC in the frame does not match the CRC calculated over its contents.
+    BadChecksum {
+        expected: u16,
+        actual: u16,
+    },
+    UnknownCommand(u8),
+}
+
+impl Display for TelegramError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooShort(len) => write!(
+                f,
+                "telegram too short: {} bytes, expected at least {}",
+                len, MIN_FRAME_LEN
+            ),
+            Self::TooLong(len) => write!(
+                f,
+                "telegram too long: {} bytes, maximum is {}",
+                len, MAX_FRAME_LEN
+            ),
+            Self::LengthMismatch { declared, actual } => write!(
+                f,
+                "length byte mismatch: declared {}, actual {}",
+                declared, actual
+            ),
+            Self::BadChecksum { expected, actual } => write!(
+                f,
+                "invalid checksum: expected {:#06x}, received {:#06x}",
+                expected, actual
+            ),
+            Self::UnknownCommand(byte) => write!(f, "unknown command byte: {:#04x}", byte),
+        }
+    }
+}
+
+impl Error for TelegramError {}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct Telegram {
     pub device_type: u16,
     pub serial_number: u32,
@@ -12,34 +74,50 @@ pub struct Telegram {
     pub data: Vec<u8>,
 }
 
-#[derive(Debug, PartialEq, Serialize, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
 pub enum Command {
     Read = 1,
     Write = 2,
     Execute = 3,
 }
 impl Command {
-    pub fn from_byte(byte: u8) -> Result<Self, &'static str> {
+    pub fn from_byte(byte: u8) -> Result<Self, TelegramError> {
         match byte {
             1 => Ok(Self::Read),
             2 => Ok(Self::Write),
             3 => Ok(Self::Execute),
-            _ => Err("invallid command byte"),
+            _ => Err(TelegramError::UnknownCommand(byte)),
         }
     }
 }
 
-impl Telegram {
-    pub fn to_bytes(&self) -> Result<Vec<u8>, &'static str> {
-        let max_length: usize = 255;
+impl FromStr for Command {
+    type Err = String;
+
+    /// Parses a command name (`read`, `write`, `execute`) or its byte value.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "read" => Ok(Self::Read),
+            "write" => Ok(Self::Write),
+            "execute" => Ok(Self::Execute),
+            other => other
+                .parse()
+                .ok()
+                .and_then(|b| Self::from_byte(b).ok())
+                .ok_or_else(|| format!("unknown command {}", s)),
+        }
+    }
+}
 
-        if self.data.len() > max_length {
-            return Err("data length exceeds maximum allowed size of 255");
+impl Telegram {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, TelegramError> {
+        if self.data.len() > MAX_DATA_LEN {
+            return Err(TelegramError::TooLong(MIN_FRAME_LEN + self.data.len()));
         }
 
         const CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_MODBUS);
 
-        let mut buffer = Vec::with_capacity(max_length);
+        let mut buffer = Vec::with_capacity(MIN_FRAME_LEN + self.data.len());
 
         buffer.extend(&self.device_type.to_be_bytes());
         buffer.extend(&self.serial_number.to_be_bytes());
@@ -52,13 +130,25 @@ impl Telegram {
         Ok(buffer)
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
+    /// Decodes a frame, rejecting it when the length byte disagrees with the frame size.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TelegramError> {
+        match Self::from_bytes_lenient(bytes)? {
+            (_, Some(warning)) => Err(warning),
+            (telegram, None) => Ok(telegram),
+        }
+    }
+
+    /// Decodes a frame like [`Telegram::from_bytes`], but returns a length byte mismatch as a
+    /// warning next to the telegram instead of failing. The payload is taken from the frame size.
+    pub fn from_bytes_lenient(
+        bytes: &[u8],
+    ) -> Result<(Self, Option<TelegramError>), TelegramError> {
         let len = bytes.len();
-        if len < 9 {
-            return Err("length to low");
+        if len < MIN_FRAME_LEN {
+            return Err(TelegramError::TooShort(len));
         }
-        if len > 255 {
-            return Err("length to high");
+        if len > MAX_FRAME_LEN {
+            return Err(TelegramError::TooLong(len));
         }
 
         const CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_MODBUS);
@@ -67,7 +157,10 @@ impl Telegram {
         let expected_checksum = CRC.checksum(&bytes[..len - 2]);
 
         if checksum != expected_checksum {
-            return Err("Invallid checksum");
+            return Err(TelegramError::BadChecksum {
+                expected: expected_checksum,
+                actual: checksum,
+            });
         }
 
         let telegram = Telegram {
@@ -77,7 +170,13 @@ impl Telegram {
             subcommand: bytes[8],
             data: bytes[9..len - 2].to_vec(),
         };
-        Ok(telegram)
+
+        let declared = bytes[HEADER_LEN - 1];
+        let actual = len - HEADER_LEN;
+        let warning = (declared as usize != actual)
+            .then_some(TelegramError::LengthMismatch { declared, actual });
+
+        Ok((telegram, warning))
     }
 }
 impl Display for Telegram {
@@ -94,6 +193,42 @@ impl Display for Telegram {
     }
 }
 
+/// Parses hex bytes separated by whitespace or commas, with or without `0x` prefix. Tokens
+/// longer than a byte are split into pairs, so `0E92FF` is read as three bytes. On failure the
+/// offending token is returned.
+pub fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    for token in text.split(|c: char| c.is_whitespace() || c == ',') {
+        let digits = token
+            .strip_prefix("0x")
+            .or_else(|| token.strip_prefix("0X"))
+            .unwrap_or(token);
+        if digits.is_empty() {
+            continue;
+        }
+        if digits.len() % 2 != 0 && digits.len() > 1 {
+            return Err(token.to_string());
+        }
+        for i in (0..digits.len()).step_by(2) {
+            let byte = digits
+                .get(i..(i + 2).min(digits.len()))
+                .and_then(|d| u8::from_str_radix(d, 16).ok())
+                .ok_or_else(|| token.to_string())?;
+            bytes.push(byte);
+        }
+    }
+    Ok(bytes)
+}
+
+/// Formats bytes as space separated hex, e.g. `0E 92 FF`.
+pub fn format_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -137,4 +272,67 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(
+            parse_hex("0E 92,0xff 0XFF"),
+            Ok(vec![0x0E, 0x92, 0xFF, 0xFF])
+        );
+        assert_eq!(parse_hex("0E92ff\n04"), Ok(vec![0x0E, 0x92, 0xFF, 0x04]));
+        assert_eq!(parse_hex("0E 92F"), Err("92F".to_string()));
+        assert_eq!(parse_hex("0xG1"), Err("0xG1".to_string()));
+    }
+
+    #[test]
+    fn test_from_bytes_errors() {
+        assert_eq!(
+            Telegram::from_bytes(&[0x0E, 0x92, 0xFF, 0xFF, 0xFF, 0xFF, 0x04, 0x01, 0xCC]),
+            Err(TelegramError::TooShort(9))
+        );
+        assert_eq!(
+            Telegram::from_bytes(&[0u8; 256]),
+            Err(TelegramError::TooLong(256))
+        );
+        assert_eq!(
+            Telegram::from_bytes(&[
+                0x0E, 0x92, 0xFF, 0xFF, 0xFF, 0xFF, 0x04, 0x01, 0xCC, 0xB1, 0x22
+            ]),
+            Err(TelegramError::BadChecksum {
+                expected: 0x21B1,
+                actual: 0x22B1
+            })
+        );
+        assert_eq!(Command::from_byte(4), Err(TelegramError::UnknownCommand(4)));
+    }
+
+    #[test]
+    fn test_from_bytes_length_mismatch() {
+        // Valid CRC, but the length byte claims 5 instead of 6.
+        let bytes = [
+            0x0E, 0x92, 0x00, 0x7B, 0x9E, 0x98, 0x05, 0x01, 0xD2, 0x00, 0x31, 0x1C, 0xEC,
+        ];
+        let mismatch = TelegramError::LengthMismatch {
+            declared: 5,
+            actual: 6,
+        };
+        assert_eq!(Telegram::from_bytes(&bytes), Err(mismatch));
+
+        let (telegram, warning) = Telegram::from_bytes_lenient(&bytes).unwrap();
+        assert_eq!(warning, Some(mismatch));
+        assert_eq!(telegram.subcommand, 0xD2);
+        assert_eq!(telegram.data, vec![0x00, 0x31]);
+    }
+
+    #[test]
+    fn test_to_bytes_too_long() {
+        let telegram = Telegram {
+            device_type: 3730,
+            serial_number: 0xFFFFFFFF,
+            command: Command::Write,
+            subcommand: 1,
+            data: vec![0; MAX_DATA_LEN + 1],
+        };
+        assert_eq!(telegram.to_bytes(), Err(TelegramError::TooLong(256)));
+    }
 }
@@ -1,62 +1,230 @@
 use colored::Colorize;
 use std::time::Duration;
 
-use bluer::gatt::remote::{Characteristic, CharacteristicWriteRequest};
-use futures::{pin_mut, StreamExt};
-use tokio::time::{sleep, timeout};
+use tokio::time::{sleep, Instant};
 
-use crate::ble::telegram::Telegram;
+use crate::ble::{
+    client::{Client, RequestError},
+    expect::Expectation,
+    registry::Registry,
+    telegram::Telegram,
+    transport::TelegramTransport,
+};
 
-pub struct EventSequence {
-    pub sequence: Vec<Telegram>,
+/// One request of a sequence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub name: Option<String>,
+    pub telegram: Telegram,
+    /// Pause before the request, the first step of a sequence is sent right away.
     pub delay: Duration,
+    /// Checks the response has to pass, none when any response will do.
+    pub expect: Vec<Expectation>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct EventSequence {
+    pub steps: Vec<Step>,
+}
+
+/// The result of one request of a sequence.
+#[derive(Debug, PartialEq)]
+pub struct Outcome {
+    pub result: Result<Telegram, RequestError>,
+    /// Time from sending the request to its response or failure, retries included.
+    pub elapsed: Duration,
+    /// Whether the response met the expectations of the step, `None` without expectations.
+    pub check: Option<Result<(), String>>,
 }
 
 impl EventSequence {
-    pub async fn send(&self, char: Characteristic) -> bluer::Result<()> {
-        let write_req = CharacteristicWriteRequest {
-            op_type: bluer::gatt::WriteOp::Request,
-            ..Default::default()
-        };
+    /// Sends `telegrams` with `delay` between them.
+    pub fn new(telegrams: Vec<Telegram>, delay: Duration) -> Self {
+        EventSequence {
+            steps: telegrams
+                .into_iter()
+                .map(|telegram| Step {
+                    name: None,
+                    telegram,
+                    delay,
+                    expect: Vec::new(),
+                })
+                .collect(),
+        }
+    }
 
-        let notify = char.notify().await?;
-        pin_mut!(notify);
-
-        println!(
-            "Starting write sequence ({:?}, {})",
-            self.delay,
-            self.sequence.len()
-        );
-
-        let mut first = true;
-        for telegram in self.sequence.as_slice() {
-            if first {
-                first = false;
-            } else {
-                sleep(self.delay).await;
-            }
-
-            print!("{}: {}...", "Request".blue(), telegram);
-            let bytes = telegram.to_bytes().unwrap();
-            println!("done");
-
-            char.write_ext(&bytes, &write_req).await?;
-
-            match timeout(Duration::from_millis(1500), notify.next()).await {
-                Ok(Some(v)) => match Telegram::from_bytes(v.as_slice()) {
-                    Ok(r) => println!("{}: {}", "Response".green(), r),
-                    Err(er) => println!("   Error in response {}", er),
-                },
-                Ok(None) => println!("    End of messages"),
-                Err(e) => println!(
-                    "    {}{}{}",
-                    "Timeout while reading response, ".yellow(),
-                    "Error: ".red(),
-                    e
-                ),
+    /// Sends the telegrams one by one and prints every request and response.
+    pub async fn send<T: TelegramTransport>(
+        &self,
+        client: &mut Client<T>,
+        registry: &Registry,
+    ) -> Vec<Outcome> {
+        println!("Starting write sequence ({} steps)", self.steps.len());
+
+        self.run(client, |step, outcome, strays| {
+            if let Some(name) = &step.name {
+                println!("{} {}", "Step".bold(), name);
+            }
+            println!("{}: {}", "Request".blue(), registry.display(&step.telegram));
+            for stray in strays {
+                println!("{}: {}", "Stray".yellow(), registry.display(stray));
+            }
+            match &outcome.result {
+                Ok(r) => println!("{}: {}", "Response".green(), registry.display(r)),
+                Err(e) => println!("    {}{}", "Error: ".red(), e),
+            }
+            match &outcome.check {
+                Some(Ok(())) => println!("{}: {}", "Expect".blue(), "PASS".green()),
+                Some(Err(mismatch)) => {
+                    println!("{}: {} {}", "Expect".blue(), "FAIL".red(), mismatch)
+                }
+                None => {}
             }
             println!();
+        })
+        .await
+    }
+
+    /// Sends the steps one by one and calls `report` with every step, its outcome and the stray
+    /// notifications received meanwhile. Stops early when the transport closes or a request
+    /// can't be written, the following requests would only time out.
+    pub async fn run<T, F>(&self, client: &mut Client<T>, mut report: F) -> Vec<Outcome>
+    where
+        T: TelegramTransport,
+        F: FnMut(&Step, &Outcome, &[Telegram]),
+    {
+        let mut outcomes = Vec::with_capacity(self.steps.len());
+        for (i, step) in self.steps.iter().enumerate() {
+            if i > 0 {
+                sleep(step.delay).await;
+            }
+
+            let start = Instant::now();
+            let result = client.request(&step.telegram).await;
+            let elapsed = start.elapsed();
+            let check = (!step.expect.is_empty()).then(|| match &result {
+                Ok(response) => step.expect.iter().try_for_each(|e| e.check(response)),
+                Err(e) => Err(e.to_string()),
+            });
+            let outcome = Outcome {
+                result,
+                elapsed,
+                check,
+            };
+            report(step, &outcome, &client.take_strays());
+
+            let failed = matches!(
+                outcome.result,
+                Err(RequestError::Closed | RequestError::Send(_))
+            );
+            outcomes.push(outcome);
+            if failed {
+                break;
+            }
         }
-        Ok(())
+        outcomes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::prefab::greet;
+    use crate::ble::script::Script;
+    use crate::ble::transport::LoopbackTransport;
+
+    #[tokio::test]
+    async fn test_send_over_loopback() {
+        let request = greet();
+        let sequence = EventSequence::new(vec![request.clone(); 3], Duration::from_millis(1));
+        let (mut transport, mut device) = LoopbackTransport::pair();
+
+        let echo = async {
+            let mut received = 0;
+            while let Some(t) = device.recv().await {
+                device.send(&t).unwrap();
+                received += 1;
+            }
+            received
+        };
+        let send = async {
+            let mut client = Client::new(&mut transport, Default::default());
+            let results = sequence.send(&mut client, &Registry::builtin()).await;
+            client.into_inner().close().await.unwrap();
+            results
+        };
+
+        let (received, results) = futures::join!(echo, send);
+        assert_eq!(received, 3);
+        assert!(results.iter().all(|o| o.result.as_ref() == Ok(&request)));
+        assert!(results.iter().all(|o| o.check.is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_script_expectations() {
+        let script: Script = toml::from_str(
+            r#"
+            [[step]]
+            command = "read"
+            subcommand = "greet"
+            expect = { pattern = "0E 92 FF FF FF FF 04 01 CC *" }
+
+            [[step]]
+            command = "read"
+            subcommand = "greet"
+            expect = { subcommand = 101 }
+
+            [[step]]
+            hex = "0E 92 FF FF FF FF 04 01 CC B1 21"
+            "#,
+        )
+        .unwrap();
+        let sequence = script.compile(&Registry::builtin()).unwrap();
+        let (mut transport, mut device) = LoopbackTransport::pair();
+
+        // Answers every request as device 3730.
+        let respond = async {
+            while let Some(mut t) = device.recv().await {
+                t.device_type = 3730;
+                device.send(&t).unwrap();
+            }
+        };
+        let send = async {
+            let mut client = Client::new(&mut transport, Default::default());
+            let results = sequence.send(&mut client, &Registry::builtin()).await;
+            client.into_inner().close().await.unwrap();
+            results
+        };
+
+        let ((), results) = futures::join!(respond, send);
+        assert!(results.iter().all(|o| o.result.is_ok()));
+        assert_eq!(results[0].check, Some(Ok(())));
+        assert!(results[1].check.as_ref().unwrap().is_err());
+        assert_eq!(results[2].check, None);
+    }
+
+    #[tokio::test]
+    async fn test_stop_on_send_failure() {
+        let request = greet();
+        let sequence = EventSequence::new(vec![request.clone(); 4], Duration::ZERO);
+        let (mut transport, mut device) = LoopbackTransport::pair();
+
+        // The device answers the first request and goes away, so writing the second fails.
+        let answer = async move {
+            let t = device.recv().await.unwrap();
+            device.send(&t).unwrap();
+        };
+        let send = async {
+            let mut client = Client::new(&mut transport, Default::default());
+            let mut reported = 0;
+            let results = sequence.run(&mut client, |_, _, _| reported += 1).await;
+            (reported, results)
+        };
+
+        let ((), (reported, results)) = futures::join!(answer, send);
+        assert_eq!(reported, 2);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].result, Ok(request));
+        assert!(matches!(results[1].result, Err(RequestError::Send(_))));
     }
 }
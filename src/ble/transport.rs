@@ -0,0 +1,454 @@
+use std::pin::Pin;
+
+use anyhow::{anyhow, Result};
+use bluer::gatt::remote::{Characteristic, CharacteristicWriteRequest};
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    stream, Stream, StreamExt,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{tcp::OwnedWriteHalf, TcpStream},
+};
+
+use crate::ble::{
+    framer::{framed, tapped, NotificationTap},
+    framing::{FrameError, FrameReader, Framing, StreamFraming},
+    telegram::{Telegram, TelegramError},
+};
+
+type TelegramStream = Pin<Box<dyn Stream<Item = Result<Telegram, TelegramError>>>>;
+type NotifyStream = Pin<Box<dyn Stream<Item = Vec<u8>>>>;
+
+/// A link to a BlueSmile module: telegrams on the testbench characteristic and raw commands on
+/// the control point. Timeouts are left to the caller.
+// The CLI runs on a current thread runtime, so the futures don't need to be `Send`.
+#[allow(async_fn_in_trait)]
+pub trait TelegramTransport {
+    async fn send(&mut self, telegram: &Telegram) -> Result<()>;
+
+    /// The next telegram from the device, `None` once the link is closed.
+    async fn recv(&mut self) -> Option<Result<Telegram, TelegramError>>;
+
+    /// Writes a serialized [`ControlCommand`](crate::protocol::ControlCommand) to the control
+    /// point.
+    async fn send_control(&mut self, command: &[u8]) -> Result<()>;
+
+    /// The next notification of the control point, `None` once the link is closed.
+    async fn recv_control(&mut self) -> Option<Vec<u8>>;
+
+    async fn close(&mut self) -> Result<()>;
+
+    /// Keeps the testbench notifications as received from now on, for
+    /// [`TelegramTransport::take_notifications`]. `false` when the transport has no
+    /// notifications, e.g. because it reads whole frames.
+    fn keep_notifications(&mut self) -> bool {
+        false
+    }
+
+    /// The notifications kept since the last call, in the order they were received.
+    fn take_notifications(&mut self) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+
+    /// The received telegrams as a stream.
+    fn incoming(&mut self) -> impl Stream<Item = Result<Telegram, TelegramError>> + '_
+    where
+        Self: Sized,
+    {
+        stream::unfold(self, |transport| async move {
+            transport.recv().await.map(|t| (t, transport))
+        })
+    }
+}
+
+impl<T: TelegramTransport> TelegramTransport for &mut T {
+    async fn send(&mut self, telegram: &Telegram) -> Result<()> {
+        (**self).send(telegram).await
+    }
+
+    async fn recv(&mut self) -> Option<Result<Telegram, TelegramError>> {
+        (**self).recv().await
+    }
+
+    async fn send_control(&mut self, command: &[u8]) -> Result<()> {
+        (**self).send_control(command).await
+    }
+
+    async fn recv_control(&mut self) -> Option<Vec<u8>> {
+        (**self).recv_control().await
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        (**self).close().await
+    }
+
+    fn keep_notifications(&mut self) -> bool {
+        (**self).keep_notifications()
+    }
+
+    fn take_notifications(&mut self) -> Vec<Vec<u8>> {
+        (**self).take_notifications()
+    }
+}
+
+/// The GATT characteristics of a connected device. Notifications are subscribed when a
+/// characteristic is added, so no response can be missed.
+#[derive(Default)]
+pub struct GattTransport {
+    testbench: Option<(Characteristic, TelegramStream)>,
+    control_point: Option<(Characteristic, NotifyStream)>,
+    notifications: NotificationTap,
+}
+
+impl GattTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn with_testbench(mut self, char: Characteristic) -> Result<Self> {
+        let notify = framed(tapped(char.notify().await?, self.notifications.clone()));
+        self.testbench = Some((char, Box::pin(notify)));
+        Ok(self)
+    }
+
+    pub async fn with_control_point(mut self, char: Characteristic) -> Result<Self> {
+        let notify = char.notify().await?;
+        self.control_point = Some((char, Box::pin(notify)));
+        Ok(self)
+    }
+
+    fn write_request() -> CharacteristicWriteRequest {
+        CharacteristicWriteRequest {
+            op_type: bluer::gatt::WriteOp::Request,
+            ..Default::default()
+        }
+    }
+}
+
+impl TelegramTransport for GattTransport {
+    async fn send(&mut self, telegram: &Telegram) -> Result<()> {
+        let (char, _) = self
+            .testbench
+            .as_ref()
+            .ok_or_else(|| anyhow!("no testbench characteristic"))?;
+        char.write_ext(&telegram.to_bytes()?, &Self::write_request())
+            .await?;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Option<Result<Telegram, TelegramError>> {
+        self.testbench.as_mut()?.1.next().await
+    }
+
+    async fn send_control(&mut self, command: &[u8]) -> Result<()> {
+        let (char, _) = self
+            .control_point
+            .as_ref()
+            .ok_or_else(|| anyhow!("no control point characteristic"))?;
+        char.write_ext(command, &Self::write_request()).await?;
+        Ok(())
+    }
+
+    async fn recv_control(&mut self) -> Option<Vec<u8>> {
+        self.control_point.as_mut()?.1.next().await
+    }
+
+    /// Unsubscribes from the notifications, the device stays connected.
+    async fn close(&mut self) -> Result<()> {
+        self.testbench = None;
+        self.control_point = None;
+        Ok(())
+    }
+
+    fn keep_notifications(&mut self) -> bool {
+        self.notifications.enable();
+        true
+    }
+
+    fn take_notifications(&mut self) -> Vec<Vec<u8>> {
+        self.notifications.take()
+    }
+}
+
+/// In-memory transport, the other end is a [`LoopbackDevice`]. Bytes written by the device are
+/// framed like GATT notifications, so framing errors can be tested as well.
+pub struct LoopbackTransport {
+    requests: Option<UnboundedSender<Telegram>>,
+    responses: TelegramStream,
+    control_requests: Option<UnboundedSender<Vec<u8>>>,
+    control_responses: UnboundedReceiver<Vec<u8>>,
+    notifications: NotificationTap,
+}
+
+/// Something written to a [`LoopbackDevice`].
+#[derive(Debug, PartialEq)]
+pub enum LoopbackRequest {
+    Telegram(Telegram),
+    Control(Vec<u8>),
+}
+
+/// The device end of a [`LoopbackTransport`].
+pub struct LoopbackDevice {
+    requests: UnboundedReceiver<Telegram>,
+    responses: UnboundedSender<Vec<u8>>,
+    control_requests: UnboundedReceiver<Vec<u8>>,
+    control_responses: UnboundedSender<Vec<u8>>,
+}
+
+impl LoopbackTransport {
+    pub fn pair() -> (LoopbackTransport, LoopbackDevice) {
+        let (requests_tx, requests_rx) = mpsc::unbounded();
+        let (responses_tx, responses_rx) = mpsc::unbounded();
+        let (control_requests_tx, control_requests_rx) = mpsc::unbounded();
+        let (control_responses_tx, control_responses_rx) = mpsc::unbounded();
+        let notifications = NotificationTap::default();
+        (
+            LoopbackTransport {
+                requests: Some(requests_tx),
+                responses: Box::pin(framed(tapped(responses_rx, notifications.clone()))),
+                control_requests: Some(control_requests_tx),
+                control_responses: control_responses_rx,
+                notifications,
+            },
+            LoopbackDevice {
+                requests: requests_rx,
+                responses: responses_tx,
+                control_requests: control_requests_rx,
+                control_responses: control_responses_tx,
+            },
+        )
+    }
+}
+
+impl TelegramTransport for LoopbackTransport {
+    async fn send(&mut self, telegram: &Telegram) -> Result<()> {
+        // Fail like a real write would on a telegram that can't be encoded.
+        telegram.to_bytes()?;
+        self.requests
+            .as_ref()
+            .and_then(|tx| tx.unbounded_send(telegram.clone()).ok())
+            .ok_or_else(|| anyhow!("loopback transport is closed"))
+    }
+
+    async fn recv(&mut self) -> Option<Result<Telegram, TelegramError>> {
+        self.responses.next().await
+    }
+
+    async fn send_control(&mut self, command: &[u8]) -> Result<()> {
+        self.control_requests
+            .as_ref()
+            .and_then(|tx| tx.unbounded_send(command.to_vec()).ok())
+            .ok_or_else(|| anyhow!("loopback transport is closed"))
+    }
+
+    async fn recv_control(&mut self) -> Option<Vec<u8>> {
+        self.control_responses.next().await
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.requests = None;
+        self.control_requests = None;
+        Ok(())
+    }
+
+    fn keep_notifications(&mut self) -> bool {
+        self.notifications.enable();
+        true
+    }
+
+    fn take_notifications(&mut self) -> Vec<Vec<u8>> {
+        self.notifications.take()
+    }
+}
+
+impl LoopbackDevice {
+    /// The next telegram sent by the transport, `None` once it is closed.
+    pub async fn recv(&mut self) -> Option<Telegram> {
+        self.requests.next().await
+    }
+
+    pub fn send(&self, telegram: &Telegram) -> Result<()> {
+        self.send_raw(telegram.to_bytes()?)
+    }
+
+    /// Sends bytes as a single notification, they don't need to be a valid telegram.
+    pub fn send_raw(&self, bytes: Vec<u8>) -> Result<()> {
+        self.responses
+            .unbounded_send(bytes)
+            .map_err(|_| anyhow!("loopback transport is closed"))
+    }
+
+    pub async fn recv_control(&mut self) -> Option<Vec<u8>> {
+        self.control_requests.next().await
+    }
+
+    /// Ends the notifications like a dropped link, the transport reads `None` from then on.
+    pub fn close(&self) {
+        self.responses.close_channel();
+        self.control_responses.close_channel();
+    }
+
+    /// The next write to either characteristic, `None` once the transport is closed.
+    pub async fn recv_any(&mut self) -> Option<LoopbackRequest> {
+        futures::select! {
+            t = self.requests.next() => match t {
+                Some(t) => Some(LoopbackRequest::Telegram(t)),
+                None => self.control_requests.next().await.map(LoopbackRequest::Control),
+            },
+            c = self.control_requests.next() => match c {
+                Some(c) => Some(LoopbackRequest::Control(c)),
+                None => self.requests.next().await.map(LoopbackRequest::Telegram),
+            },
+        }
+    }
+
+    pub fn send_control(&self, bytes: Vec<u8>) -> Result<()> {
+        self.control_responses
+            .unbounded_send(bytes)
+            .map_err(|_| anyhow!("loopback transport is closed"))
+    }
+}
+
+/// Telegrams over a TCP connection to `pass-through` or `simulate`, in one of the
+/// [`Framing`]s. There is no control point.
+pub struct TcpTransport {
+    writer: Option<OwnedWriteHalf>,
+    framing: Framing,
+    responses: TelegramStream,
+}
+
+impl TcpTransport {
+    /// Connects to a `pass-through` or `simulate` server using `framing`. Responses that aren't
+    /// telegrams at all are skipped.
+    pub async fn connect(addr: &str, framing: StreamFraming) -> Result<Self> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        let reader = FrameReader::new(reader, framing.response);
+        let responses = stream::unfold(reader, |mut reader| async move {
+            loop {
+                match reader.read().await {
+                    Ok(None) | Err(_) => return None,
+                    Ok(Some(Ok(t))) => return Some((Ok(t), reader)),
+                    Ok(Some(Err(FrameError::Telegram(e)))) => return Some((Err(e), reader)),
+                    Ok(Some(Err(FrameError::Syntax(e)))) => eprintln!("Invalid response: {}", e),
+                }
+            }
+        });
+        Ok(TcpTransport {
+            writer: Some(writer),
+            framing: framing.request,
+            responses: Box::pin(responses),
+        })
+    }
+}
+
+impl TelegramTransport for TcpTransport {
+    async fn send(&mut self, telegram: &Telegram) -> Result<()> {
+        let bytes = self.framing.encode(telegram)?;
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or_else(|| anyhow!("tcp transport is closed"))?;
+        writer.write_all(&bytes).await?;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Option<Result<Telegram, TelegramError>> {
+        self.responses.next().await
+    }
+
+    async fn send_control(&mut self, _command: &[u8]) -> Result<()> {
+        Err(anyhow!("the control point isn't available over tcp"))
+    }
+
+    async fn recv_control(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.shutdown().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::prefab::greet;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_loopback() {
+        block_on(async {
+            let (mut transport, mut device) = LoopbackTransport::pair();
+
+            transport.send(&greet()).await.unwrap();
+            assert_eq!(device.recv().await, Some(greet()));
+
+            let mut bytes = greet().to_bytes().unwrap();
+            device.send_raw(bytes.clone()).unwrap();
+            bytes[3] ^= 0xFF;
+            device.send_raw(bytes).unwrap();
+            drop(device);
+
+            let received: Vec<_> = transport.incoming().collect().await;
+            assert_eq!(received.len(), 2);
+            assert_eq!(received[0], Ok(greet()));
+            assert!(matches!(
+                received[1],
+                Err(TelegramError::BadChecksum { .. })
+            ));
+        });
+    }
+
+    #[test]
+    fn test_loopback_control_and_close() {
+        block_on(async {
+            let (mut transport, mut device) = LoopbackTransport::pair();
+
+            transport
+                .send_control(&[2, 0x80, 0x25, 0, 0])
+                .await
+                .unwrap();
+            assert_eq!(device.recv_control().await, Some(vec![2, 0x80, 0x25, 0, 0]));
+            device.send_control(vec![0x03, 0x68, 0x00, 0x00]).unwrap();
+            assert_eq!(
+                transport.recv_control().await,
+                Some(vec![0x03, 0x68, 0x00, 0x00])
+            );
+
+            transport.close().await.unwrap();
+            assert!(transport.send(&greet()).await.is_err());
+            assert_eq!(device.recv().await, None);
+        });
+    }
+
+    #[tokio::test]
+    async fn test_tcp() {
+        use crate::ble::simulator::{Simulator, SimulatorConfig};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let framing = StreamFraming {
+            request: Framing::Json,
+            response: Framing::Hex,
+        };
+        let server = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut simulator = Simulator::new(SimulatorConfig::default());
+            simulator.serve_stream(&mut stream, framing).await.unwrap();
+        };
+        let client = async {
+            let mut transport = TcpTransport::connect(&addr, framing).await.unwrap();
+            transport.send(&greet()).await.unwrap();
+            let response = transport.recv().await.unwrap().unwrap();
+            assert!(transport.send_control(&[]).await.is_err());
+            transport.close().await.unwrap();
+            response
+        };
+        let (_, response) = futures::join!(server, client);
+        assert_eq!(response.data, b"BlueSmile");
+    }
+}
@@ -0,0 +1,536 @@
+use std::{collections::BTreeMap, env, fs, path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use bluer::{Address, Uuid};
+use serde::{Deserialize, Serialize};
+
+use crate::args::{parse_number, parse_probability};
+use crate::ble::{client::RequestOptions, simulator::SimulatorConfig, supervisor::ReconnectPolicy};
+
+/// Name of the project-local config file, looked up in the working directory.
+pub const LOCAL_CONFIG: &str = "ble.toml";
+
+const DEFAULT_TIMEOUT_MS: u64 = 1500;
+const DEFAULT_SCAN_TIMEOUT_S: u64 = 100;
+
+/// Connection settings of a single test bench. Every field is optional so profiles can be layered.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Profile {
+    pub device_name: Option<String>,
+    pub device_address: Option<String>,
+    pub adapter: Option<String>,
+    pub service_uuid: Option<String>,
+    pub testbench_uuid: Option<String>,
+    pub control_point_uuid: Option<String>,
+    /// Time to wait for a response, in milliseconds.
+    pub timeout_ms: Option<u64>,
+    /// Time to search for the device, in seconds.
+    pub scan_timeout_s: Option<u64>,
+    /// Talk to the built-in simulator instead of a device.
+    pub simulate: Option<bool>,
+    /// Times a request is repeated when no response arrives.
+    pub retries: Option<u32>,
+    /// Delay before the first retry in milliseconds, doubled for every following retry.
+    pub retry_backoff_ms: Option<u64>,
+    /// Times a dropped link is reconnected, 0 gives up right away.
+    pub reconnect_attempts: Option<u32>,
+    /// Delay before the first reconnection in milliseconds, doubled for every following one.
+    pub reconnect_backoff_ms: Option<u64>,
+    /// Upper bound of the delay between reconnections, in milliseconds.
+    pub reconnect_max_backoff_ms: Option<u64>,
+    /// Device type of the simulated device.
+    pub simulator_device_type: Option<u16>,
+    /// Serial number of the simulated device.
+    pub simulator_serial: Option<u32>,
+    /// Delay before every simulated response, in milliseconds.
+    pub simulator_latency_ms: Option<u64>,
+    /// Probability that the simulator doesn't answer a request.
+    pub simulator_drop_rate: Option<f64>,
+    /// Probability that the simulator corrupts the CRC of a response.
+    pub simulator_corrupt_rate: Option<f64>,
+    /// Seed for the simulated drops and corruption.
+    pub simulator_seed: Option<u64>,
+}
+
+impl Profile {
+    /// Fields set in `other` replace those in `self`. The device name and address are replaced
+    /// together, so a name in `other` isn't shadowed by an address in `self`.
+    pub fn merge(self, other: Profile) -> Profile {
+        let (device_name, device_address) =
+            if other.device_name.is_some() || other.device_address.is_some() {
+                (other.device_name, other.device_address)
+            } else {
+                (self.device_name, self.device_address)
+            };
+        Profile {
+            device_name,
+            device_address,
+            adapter: other.adapter.or(self.adapter),
+            service_uuid: other.service_uuid.or(self.service_uuid),
+            testbench_uuid: other.testbench_uuid.or(self.testbench_uuid),
+            control_point_uuid: other.control_point_uuid.or(self.control_point_uuid),
+            timeout_ms: other.timeout_ms.or(self.timeout_ms),
+            scan_timeout_s: other.scan_timeout_s.or(self.scan_timeout_s),
+            simulate: other.simulate.or(self.simulate),
+            retries: other.retries.or(self.retries),
+            retry_backoff_ms: other.retry_backoff_ms.or(self.retry_backoff_ms),
+            reconnect_attempts: other.reconnect_attempts.or(self.reconnect_attempts),
+            reconnect_backoff_ms: other.reconnect_backoff_ms.or(self.reconnect_backoff_ms),
+            reconnect_max_backoff_ms: other
+                .reconnect_max_backoff_ms
+                .or(self.reconnect_max_backoff_ms),
+            simulator_device_type: other.simulator_device_type.or(self.simulator_device_type),
+            simulator_serial: other.simulator_serial.or(self.simulator_serial),
+            simulator_latency_ms: other.simulator_latency_ms.or(self.simulator_latency_ms),
+            simulator_drop_rate: other.simulator_drop_rate.or(self.simulator_drop_rate),
+            simulator_corrupt_rate: other.simulator_corrupt_rate.or(self.simulator_corrupt_rate),
+            simulator_seed: other.simulator_seed.or(self.simulator_seed),
+        }
+    }
+
+    /// Sets the device from a value that is either an address or a name.
+    pub fn with_device(mut self, device: &str) -> Profile {
+        if Address::from_str(device).is_ok() {
+            self.device_address = Some(device.to_string());
+        } else {
+            self.device_name = Some(device.to_string());
+        }
+        self
+    }
+
+    /// Values from the environment (and `.env`), using the variable names of the original `.env`.
+    pub fn from_env() -> Result<Profile> {
+        let var = |name: &str| env::var(name).ok().filter(|v| !v.is_empty());
+        let number = |name: &str| -> Result<Option<u64>> {
+            var(name)
+                .map(|v| {
+                    v.parse()
+                        .with_context(|| format!("{} is not a number", name))
+                })
+                .transpose()
+        };
+        let rate = |name: &str| -> Result<Option<f64>> {
+            var(name)
+                .map(|v| parse_probability(&v).map_err(|e| anyhow!("{}: {}", name, e)))
+                .transpose()
+        };
+        Ok(Profile {
+            device_name: var("DEVICE_NAME"),
+            device_address: var("DEVICE_ADDRESS"),
+            adapter: var("BLE_ADAPTER"),
+            service_uuid: var("SERVICE_UUID"),
+            testbench_uuid: var("TESTBENCH"),
+            control_point_uuid: var("CONTROL_POINT"),
+            timeout_ms: number("BLE_TIMEOUT_MS")?,
+            scan_timeout_s: number("BLE_SCAN_TIMEOUT_S")?,
+            simulate: var("BLE_SIMULATE").map(|v| v != "0" && v != "false"),
+            retries: number("BLE_RETRIES")?
+                .map(|v| u32::try_from(v).context("BLE_RETRIES is too large"))
+                .transpose()?,
+            retry_backoff_ms: number("BLE_RETRY_BACKOFF_MS")?,
+            reconnect_attempts: number("BLE_RECONNECT_ATTEMPTS")?
+                .map(|v| u32::try_from(v).context("BLE_RECONNECT_ATTEMPTS is too large"))
+                .transpose()?,
+            reconnect_backoff_ms: number("BLE_RECONNECT_BACKOFF_MS")?,
+            reconnect_max_backoff_ms: number("BLE_RECONNECT_MAX_BACKOFF_MS")?,
+            simulator_device_type: var("BLE_SIMULATOR_DEVICE_TYPE")
+                .map(|v| parse_number(&v))
+                .transpose()
+                .map_err(|e| anyhow!("BLE_SIMULATOR_DEVICE_TYPE: {}", e))?,
+            simulator_serial: var("BLE_SIMULATOR_SERIAL")
+                .map(|v| parse_number(&v))
+                .transpose()
+                .map_err(|e| anyhow!("BLE_SIMULATOR_SERIAL: {}", e))?,
+            simulator_latency_ms: number("BLE_SIMULATOR_LATENCY_MS")?,
+            simulator_drop_rate: rate("BLE_SIMULATOR_DROP_RATE")?,
+            simulator_corrupt_rate: rate("BLE_SIMULATOR_CORRUPT_RATE")?,
+            simulator_seed: number("BLE_SIMULATOR_SEED")?,
+        })
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ConfigFile {
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profile: BTreeMap<String, Profile>,
+}
+
+impl ConfigFile {
+    /// Combines two config files, profiles in `other` are merged over those in `self`.
+    pub fn merge(mut self, other: ConfigFile) -> ConfigFile {
+        for (name, profile) in other.profile {
+            let merged = self
+                .profile
+                .remove(&name)
+                .unwrap_or_default()
+                .merge(profile);
+            self.profile.insert(name, merged);
+        }
+        ConfigFile {
+            default_profile: other.default_profile.or(self.default_profile),
+            profile: self.profile,
+        }
+    }
+}
+
+/// The user config file, `$XDG_CONFIG_HOME/ble/config.toml` or `~/.config/ble/config.toml`.
+pub fn user_config_path() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("ble").join("config.toml"))
+}
+
+fn read_config_file(path: PathBuf) -> Result<ConfigFile> {
+    if !path.exists() {
+        return Ok(ConfigFile::default());
+    }
+    let content =
+        fs::read_to_string(&path).with_context(|| format!("could not read {}", path.display()))?;
+    toml::from_str(&content).with_context(|| format!("invalid config {}", path.display()))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceSelector {
+    Name(String),
+    Address(Address),
+    /// Let the user pick from the devices found while scanning.
+    Interactive,
+}
+
+/// The resolved settings: a profile from the config files, overridden by the environment and
+/// the command line.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub profile_name: Option<String>,
+    pub settings: Profile,
+    pub interactive: bool,
+    /// Whether the command line selects the device, see [`Config::device_or_interactive`].
+    pub device_given: bool,
+}
+
+impl Config {
+    /// Loads the user and project-local config files and selects `profile`, falling back to
+    /// `BLE_PROFILE` and the file's `default_profile`. Environment variables take precedence over
+    /// the files and `overrides` (the command line flags) over everything.
+    pub fn load(profile: Option<&str>, overrides: Profile, interactive: bool) -> Result<Config> {
+        dotenv::dotenv().ok();
+
+        let mut file = ConfigFile::default();
+        if let Some(path) = user_config_path() {
+            file = file.merge(read_config_file(path)?);
+        }
+        file = file.merge(read_config_file(PathBuf::from(LOCAL_CONFIG))?);
+
+        let profile_name = profile
+            .map(str::to_string)
+            .or_else(|| env::var("BLE_PROFILE").ok())
+            .or(file.default_profile.clone());
+
+        let settings = match &profile_name {
+            Some(name) => file.profile.remove(name).ok_or_else(|| {
+                let available: Vec<_> = file.profile.keys().cloned().collect();
+                if available.is_empty() {
+                    anyhow!("profile '{}' not found, no profiles are configured", name)
+                } else {
+                    anyhow!(
+                        "profile '{}' not found, available: {}",
+                        name,
+                        available.join(", ")
+                    )
+                }
+            })?,
+            None => Profile::default(),
+        };
+
+        let device_given = overrides.device_name.is_some() || overrides.device_address.is_some();
+        Ok(Config {
+            profile_name,
+            settings: settings.merge(Profile::from_env()?).merge(overrides),
+            interactive,
+            device_given,
+        })
+    }
+
+    fn missing(&self, field: &str, var: &str) -> anyhow::Error {
+        match &self.profile_name {
+            Some(name) => anyhow!(
+                "{} is not configured: set {} in profile '{}' or the {} environment variable",
+                field,
+                field,
+                name,
+                var
+            ),
+            None => anyhow!(
+                "{} is not configured: set {} in a profile or the {} environment variable",
+                field,
+                field,
+                var
+            ),
+        }
+    }
+
+    fn uuid(&self, value: &Option<String>, field: &str, var: &str) -> Result<Uuid> {
+        let value = value.as_deref().ok_or_else(|| self.missing(field, var))?;
+        Uuid::from_str(value).with_context(|| format!("{} '{}' is not a valid UUID", field, value))
+    }
+
+    /// The device to connect to, an address takes precedence over a name.
+    pub fn device(&self) -> Result<DeviceSelector> {
+        if self.interactive {
+            return Ok(DeviceSelector::Interactive);
+        }
+        if let Some(address) = &self.settings.device_address {
+            return Address::from_str(address)
+                .map(DeviceSelector::Address)
+                .map_err(|_| anyhow!("device_address '{}' is not a valid address", address));
+        }
+        match &self.settings.device_name {
+            Some(name) => Ok(DeviceSelector::Name(name.clone())),
+            None => Err(self.missing("device_name", "DEVICE_NAME")),
+        }
+    }
+
+    /// The device given on the command line, otherwise the user picks one from a scan. A device
+    /// from the profile or the environment doesn't skip the picker, so `scan` keeps listing the
+    /// devices around with a `.env` in place.
+    pub fn device_or_interactive(&self) -> Result<DeviceSelector> {
+        if !self.device_given {
+            return Ok(DeviceSelector::Interactive);
+        }
+        self.device()
+    }
+
+    pub fn adapter(&self) -> Option<&str> {
+        self.settings.adapter.as_deref()
+    }
+
+    pub fn service_uuid(&self) -> Result<Uuid> {
+        self.uuid(&self.settings.service_uuid, "service_uuid", "SERVICE_UUID")
+    }
+
+    pub fn testbench_uuid(&self) -> Result<Uuid> {
+        self.uuid(&self.settings.testbench_uuid, "testbench_uuid", "TESTBENCH")
+    }
+
+    pub fn control_point_uuid(&self) -> Result<Uuid> {
+        self.uuid(
+            &self.settings.control_point_uuid,
+            "control_point_uuid",
+            "CONTROL_POINT",
+        )
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.settings.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS))
+    }
+
+    /// Timeout and retries of requests on the testbench characteristic.
+    pub fn request_options(&self) -> RequestOptions {
+        let defaults = RequestOptions::default();
+        RequestOptions {
+            timeout: self.timeout(),
+            retries: self.settings.retries.unwrap_or(defaults.retries),
+            backoff: self
+                .settings
+                .retry_backoff_ms
+                .map_or(defaults.backoff, Duration::from_millis),
+        }
+    }
+
+    /// How a dropped link to the device is reestablished.
+    pub fn reconnect_policy(&self) -> ReconnectPolicy {
+        let defaults = ReconnectPolicy::default();
+        ReconnectPolicy {
+            attempts: self
+                .settings
+                .reconnect_attempts
+                .unwrap_or(defaults.attempts),
+            backoff: self
+                .settings
+                .reconnect_backoff_ms
+                .map_or(defaults.backoff, Duration::from_millis),
+            max_backoff: self
+                .settings
+                .reconnect_max_backoff_ms
+                .map_or(defaults.max_backoff, Duration::from_millis),
+        }
+    }
+
+    pub fn simulate(&self) -> bool {
+        self.settings.simulate.unwrap_or(false)
+    }
+
+    /// The device simulated with `simulate`.
+    pub fn simulator_config(&self) -> Result<SimulatorConfig> {
+        let settings = &self.settings;
+        let rate = |value: Option<f64>, field: &str, default: f64| match value {
+            Some(rate) if !(0.0..=1.0).contains(&rate) => {
+                Err(anyhow!("{} {} is not between 0 and 1", field, rate))
+            }
+            rate => Ok(rate.unwrap_or(default)),
+        };
+        let defaults = SimulatorConfig::default();
+        Ok(SimulatorConfig {
+            device_type: settings
+                .simulator_device_type
+                .unwrap_or(defaults.device_type),
+            serial_number: settings.simulator_serial.unwrap_or(defaults.serial_number),
+            latency: settings
+                .simulator_latency_ms
+                .map_or(defaults.latency, Duration::from_millis),
+            drop_rate: rate(
+                settings.simulator_drop_rate,
+                "simulator_drop_rate",
+                defaults.drop_rate,
+            )?,
+            corrupt_rate: rate(
+                settings.simulator_corrupt_rate,
+                "simulator_corrupt_rate",
+                defaults.corrupt_rate,
+            )?,
+            seed: settings.simulator_seed.or(defaults.seed),
+            ..defaults
+        })
+    }
+
+    pub fn scan_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.settings
+                .scan_timeout_s
+                .unwrap_or(DEFAULT_SCAN_TIMEOUT_S),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_profiles() {
+        let user: ConfigFile = toml::from_str(
+            r#"
+            default_profile = "bench1"
+
+            [profile.bench1]
+            device_name = "BlueSmile"
+            service_uuid = "0000fff0-0000-1000-8000-00805f9b34fb"
+            timeout_ms = 500
+            "#,
+        )
+        .unwrap();
+        let local: ConfigFile = toml::from_str(
+            r#"
+            [profile.bench1]
+            device_address = "AA:BB:CC:DD:EE:FF"
+
+            [profile.bench2]
+            device_name = "Other"
+            "#,
+        )
+        .unwrap();
+
+        let file = user.merge(local);
+        assert_eq!(file.default_profile.as_deref(), Some("bench1"));
+        assert_eq!(file.profile.len(), 2);
+
+        let config = Config {
+            profile_name: Some("bench1".to_string()),
+            settings: file.profile["bench1"].clone(),
+            interactive: false,
+            device_given: false,
+        };
+        assert_eq!(
+            config.device().unwrap(),
+            DeviceSelector::Address(Address::new([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]))
+        );
+        assert_eq!(config.timeout(), Duration::from_millis(500));
+        assert!(config.service_uuid().is_ok());
+        assert!(config
+            .testbench_uuid()
+            .unwrap_err()
+            .to_string()
+            .contains("profile 'bench1'"));
+
+        assert_eq!(
+            config.device_or_interactive().unwrap(),
+            DeviceSelector::Interactive
+        );
+
+        let overridden = Config {
+            settings: config
+                .settings
+                .clone()
+                .merge(Profile::default().with_device("BlueSmile-2")),
+            device_given: true,
+            ..config
+        };
+        assert_eq!(
+            overridden.device().unwrap(),
+            DeviceSelector::Name("BlueSmile-2".to_string())
+        );
+        assert_eq!(
+            overridden.device_or_interactive().unwrap(),
+            DeviceSelector::Name("BlueSmile-2".to_string())
+        );
+    }
+
+    #[test]
+    fn test_simulator_config() {
+        let profile: Profile = toml::from_str(
+            r#"
+            simulate = true
+            simulator_device_type = 3793
+            simulator_drop_rate = 0.25
+            simulator_seed = 7
+            "#,
+        )
+        .unwrap();
+        let overrides = Profile {
+            simulator_serial: Some(42),
+            ..Default::default()
+        };
+        let mut config = Config {
+            profile_name: None,
+            settings: profile.merge(overrides),
+            interactive: false,
+            device_given: false,
+        };
+        let simulator = config.simulator_config().unwrap();
+        assert_eq!(simulator.device_type, 3793);
+        assert_eq!(simulator.serial_number, 42);
+        assert_eq!(simulator.drop_rate, 0.25);
+        assert_eq!(simulator.corrupt_rate, 0.0);
+        assert_eq!(simulator.seed, Some(7));
+
+        config.settings.simulator_corrupt_rate = Some(1.5);
+        assert_eq!(
+            config.simulator_config().unwrap_err().to_string(),
+            "simulator_corrupt_rate 1.5 is not between 0 and 1"
+        );
+    }
+
+    #[test]
+    fn test_reconnect_policy() {
+        let profile: Profile = toml::from_str(
+            r#"
+            reconnect_attempts = 3
+            reconnect_max_backoff_ms = 4000
+            "#,
+        )
+        .unwrap();
+        let config = Config {
+            profile_name: None,
+            settings: profile,
+            interactive: false,
+            device_given: false,
+        };
+        let policy = config.reconnect_policy();
+        assert_eq!(policy.attempts, 3);
+        assert_eq!(policy.backoff, ReconnectPolicy::default().backoff);
+        assert_eq!(policy.max_backoff, Duration::from_secs(4));
+        assert_eq!(policy.delay(10), Duration::from_secs(4));
+    }
+}
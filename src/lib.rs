@@ -1,5 +1,6 @@
 pub mod args;
 pub mod ble;
+pub mod config;
 pub mod protocol;
 
 pub mod subcommands {
@@ -7,8 +8,13 @@ pub mod subcommands {
     pub mod assign_passkey;
     pub mod decode;
     pub mod devices;
+    pub mod encode;
     pub mod explore;
     pub mod pass_through;
+    pub mod register;
+    pub mod replay;
     pub mod run;
     pub mod scan;
+    pub mod simulate;
+    pub mod tcp_client;
 }
@@ -1,20 +1,92 @@
 use anyhow::Result;
 use cargo_ble::args::{CliArgs, Command};
+use cargo_ble::ble::simulator::SimulatorConfig;
+use cargo_ble::config::Config;
 use cargo_ble::subcommands;
 use clap::Parser;
+use std::time::Duration;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
     let args = CliArgs::parse();
+    let profile = args.profile;
+    let connection = args.connection;
+    let config = || {
+        Config::load(
+            profile.as_deref(),
+            connection.to_profile(),
+            connection.interactive,
+        )
+    };
 
     match args.subcommand {
-        Command::Run { iterations, delay } => subcommands::run::main(iterations, delay).await,
-        Command::AssignPasskey { passkey } => subcommands::assign_passkey::main(passkey).await,
-        Command::AssignBaudrate { baudrate } => subcommands::assign_baudrate::main(baudrate).await,
-        Command::Decode { bytes, format } => subcommands::decode::main(bytes, format),
-        Command::Scan => subcommands::scan::main().await,
-        Command::Explore => subcommands::explore::main().await,
-        Command::Devices => subcommands::devices::main().await,
-        Command::PassThrough => subcommands::pass_through::main().await,
+        Command::Run(args) => subcommands::run::main(config()?, args).await,
+        Command::Replay(args) => subcommands::replay::main(config()?, args).await,
+        Command::AssignPasskey { passkey } => {
+            subcommands::assign_passkey::main(config()?, passkey).await
+        }
+        Command::AssignBaudrate { baudrate } => {
+            subcommands::assign_baudrate::main(config()?, baudrate).await
+        }
+        Command::Decode {
+            bytes,
+            input,
+            input_format,
+            output,
+            lenient,
+            registry,
+        } => subcommands::decode::main(bytes, input, input_format, output, lenient, registry),
+        Command::Encode {
+            device_type,
+            serial,
+            command,
+            subcommand,
+            data,
+            output,
+            registry,
+        } => subcommands::encode::main(
+            device_type,
+            serial,
+            command,
+            subcommand,
+            data,
+            output,
+            registry,
+        ),
+        Command::ReadRegister { register, map } => {
+            subcommands::register::read(config()?, register, map).await
+        }
+        Command::WriteRegister {
+            register,
+            value,
+            map,
+        } => subcommands::register::write(config()?, register, value, map).await,
+        Command::Scan => subcommands::scan::main(config()?).await,
+        Command::Explore { record } => subcommands::explore::main(config()?, record).await,
+        Command::Devices => subcommands::devices::main(config()?).await,
+        Command::PassThrough(args) => subcommands::pass_through::main(config()?, args).await,
+        Command::Simulate {
+            tcp,
+            unix,
+            device_type,
+            serial,
+            latency,
+            drop_rate,
+            corrupt_rate,
+            seed,
+            framing,
+        } => {
+            let simulator = SimulatorConfig {
+                device_type,
+                serial_number: serial,
+                latency: Duration::from_millis(latency),
+                drop_rate,
+                corrupt_rate,
+                seed,
+                ..Default::default()
+            };
+            subcommands::simulate::main(simulator, tcp, unix, framing.stream_framing()).await
+        }
+        Command::TcpClient(args) => subcommands::tcp_client::main(args).await,
     }
 }
@@ -1,3 +1,5 @@
+use std::{fmt::Display, str::FromStr};
+
 use crc::{Crc, CRC_16_MODBUS};
 use serde::Serialize;
 
@@ -36,6 +38,121 @@ impl ControlCommand {
     }
 }
 
+/// Subcommand of the `Read` telegram that is answered with its own data.
+pub const TESTBENCH: u8 = 101;
+/// Subcommand of the `Read` telegram that is answered with the module's greeting.
+pub const GREET: u8 = 204;
+/// Subcommand of the `Read` telegram that is answered with the longest possible payload.
+pub const BIG_RESPONSE: u8 = 206;
+
+/// Subcommand of the `Write` telegram that asks for a baudrate change, the data is the new
+/// baudrate as a big endian `u32`.
+pub const CHANGE_BAUDRATE: u8 = 210;
+
+/// The supported baudrates and the divisor the module echoes after switching to each.
+const BAUDRATES: [(u32, u32); 9] = [
+    (2400, 0x01a00b),
+    (4800, 0x00d005),
+    (9600, 0x006803),
+    (14400, 0x004507),
+    (19200, 0x003401),
+    (28800, 0x00220c),
+    (38400, 0x001a01),
+    (57600, 0x001106),
+    (115200, 0x00080b),
+];
+
+/// A baudrate the module's serial port supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Baudrate(u32);
+
+impl Baudrate {
+    pub fn all() -> impl Iterator<Item = Baudrate> {
+        BAUDRATES.iter().map(|&(rate, _)| Baudrate(rate))
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.0
+    }
+
+    /// The value the module echoes after switching to this baudrate, e.g. `0x006803` for 9600.
+    pub fn divisor(&self) -> u32 {
+        BAUDRATES
+            .iter()
+            .find(|&&(rate, _)| rate == self.0)
+            .map(|&(_, divisor)| divisor)
+            .expect("baudrates are supported")
+    }
+
+    /// The baudrate the module echoes `divisor` for.
+    pub fn from_divisor(divisor: u32) -> Option<Baudrate> {
+        BAUDRATES
+            .iter()
+            .find(|&&(_, d)| d == divisor)
+            .map(|&(rate, _)| Baudrate(rate))
+    }
+
+    /// The control point command that switches the module to this baudrate.
+    pub fn command(&self) -> ControlCommand {
+        ControlCommand::new(CommandType::BAUDRATE, self.0.to_le_bytes())
+    }
+}
+
+impl TryFrom<u32> for Baudrate {
+    type Error = String;
+
+    fn try_from(rate: u32) -> Result<Self, Self::Error> {
+        if BAUDRATES.iter().any(|&(r, _)| r == rate) {
+            return Ok(Baudrate(rate));
+        }
+        let supported: Vec<_> = Self::all().map(|b| b.to_string()).collect();
+        Err(format!(
+            "unsupported baudrate {}, expected one of {}",
+            rate,
+            supported.join(", ")
+        ))
+    }
+}
+
+impl FromStr for Baudrate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rate = s
+            .parse::<u32>()
+            .map_err(|_| format!("baudrate {} is not a number", s))?;
+        Self::try_from(rate)
+    }
+}
+
+impl Display for Baudrate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A notification of the control point: the module echoes the value it applied as the first
+/// four bytes, little endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlResponse(pub u32);
+
+impl ControlResponse {
+    /// `None` when the notification is shorter than four bytes.
+    pub fn parse(bytes: &[u8]) -> Option<ControlResponse> {
+        let value = bytes.get(..4)?.try_into().ok()?;
+        Some(ControlResponse(u32::from_le_bytes(value)))
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        self.0.to_le_bytes().to_vec()
+    }
+
+    /// The baudrate this is the echo of.
+    pub fn baudrate(&self) -> Option<Baudrate> {
+        Baudrate::from_divisor(self.0)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -44,6 +161,32 @@ mod tests {
     fn test_control_command() {
         let ctrl_cmd = ControlCommand::new(CommandType::PASSKEY, [1, 2, 3, 4]);
         let serialized = ctrl_cmd.serialize();
-        assert_eq!(vec![1], serialized);
+        assert_eq!(vec![1, 1, 2, 3, 4, 0xB8, 0xCF], serialized);
+    }
+
+    #[test]
+    fn test_baudrate() {
+        assert_eq!(Baudrate::all().count(), 9);
+        for baudrate in Baudrate::all() {
+            assert_eq!(baudrate.to_string().parse(), Ok(baudrate));
+            let echo = ControlResponse(baudrate.divisor()).serialize();
+            assert_eq!(
+                ControlResponse::parse(&echo).unwrap().baudrate(),
+                Some(baudrate)
+            );
+        }
+
+        let response = ControlResponse::parse(&[0x0b, 0x08, 0x00, 0x00]).unwrap();
+        assert_eq!(response.baudrate().map(|b| b.rate()), Some(115200));
+        assert_eq!(ControlResponse::parse(&[0x03, 0x68, 0x00]), None);
+        assert_eq!(ControlResponse(123456).baudrate(), None);
+
+        assert_eq!(Baudrate::try_from(2400).unwrap().divisor(), 0x01a00b);
+        assert!(Baudrate::try_from(1200).unwrap_err().contains("2400, 4800"));
+        assert!("fast".parse::<Baudrate>().is_err());
+        assert_eq!(
+            Baudrate::try_from(9600).unwrap().command().serialize(),
+            ControlCommand::new(CommandType::BAUDRATE, 9600u32.to_le_bytes()).serialize()
+        );
     }
 }
@@ -1,40 +1,467 @@
-use crate::ble::telegram::Telegram;
-use crate::protocol::{CommandType, ControlCommand};
-use anyhow::Result;
-
-pub fn main(data: Vec<String>, format: bool) -> Result<()> {
-    let cmd = ControlCommand::new(CommandType::PASSKEY, 123456u32.to_le_bytes());
-    println!("cmd: {:?}", cmd.serialize());
-
-    let bytes: Vec<u8> = data
-        .as_slice()
-        .iter()
-        .map(|v| u8::from_str_radix(v, 16).unwrap())
-        .collect();
-
-    match Telegram::from_bytes(&bytes) {
-        Ok(command) => {
-            if format {
-                println!(
+use crate::args::{InputFormat, OutputFormat};
+use crate::ble::framer::TelegramFramer;
+use crate::ble::registry::Registry;
+use crate::ble::telegram::{format_hex, parse_hex, Telegram, TelegramError, HEADER_LEN};
+use anyhow::{anyhow, Context, Result};
+use colored::Colorize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Stdout};
+use std::path::{Path, PathBuf};
+
+pub fn main(
+    data: Vec<String>,
+    input: Option<PathBuf>,
+    input_format: InputFormat,
+    output: OutputFormat,
+    lenient: bool,
+    registry: Option<PathBuf>,
+) -> Result<()> {
+    let registry = Registry::load(registry.as_deref())?;
+    let mut printer = Printer::new(output, &registry);
+
+    let Some(input) = input else {
+        let bytes = parse_hex(&data.join(" ")).map_err(|t| anyhow!("invalid hex byte {}", t))?;
+        printer.print(&Report::new(&bytes, lenient, &registry))?;
+        return printer.flush();
+    };
+
+    let content = read_input(&input)?;
+    let mut summary = Summary::default();
+    for report in decode_input(content, input_format, lenient, &registry)? {
+        summary.add(&report, &registry);
+        printer.print(&report)?;
+    }
+
+    printer.flush()?;
+    summary.print();
+    Ok(())
+}
+
+/// The reports of every frame in `content`. A line that isn't hex is reported with its number,
+/// decoding continues with the next line.
+fn decode_input(
+    content: Vec<u8>,
+    input_format: InputFormat,
+    lenient: bool,
+    registry: &Registry,
+) -> Result<Vec<Report>> {
+    let mut reports = Vec::new();
+    if let InputFormat::Binary = input_format {
+        let mut framer = TelegramFramer::new();
+        framer.push(&content);
+        reports.extend(frames(&mut framer, lenient, registry));
+        report_trailing(&framer);
+        return Ok(reports);
+    }
+
+    let text = String::from_utf8(content).context("input is not text")?;
+    let mut framer = TelegramFramer::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let bytes = match parse_hex(line) {
+            Ok(bytes) => bytes,
+            Err(token) => {
+                reports.push(Report::invalid_hex(&token).at_line(i + 1));
+                continue;
+            }
+        };
+        match input_format {
+            InputFormat::Lines => {
+                reports.push(Report::new(&bytes, lenient, registry).at_line(i + 1))
+            }
+            // Telegrams may span lines, they are reported at the line that completes them.
+            _ => {
+                framer.push(&bytes);
+                let framed = frames(&mut framer, lenient, registry);
+                reports.extend(framed.into_iter().map(|r| r.at_line(i + 1)));
+            }
+        }
+    }
+    report_trailing(&framer);
+    Ok(reports)
+}
+
+fn frames(framer: &mut TelegramFramer, lenient: bool, registry: &Registry) -> Vec<Report> {
+    let mut reports = Vec::new();
+    while let Some(frame) = framer.next_frame() {
+        reports.push(match frame {
+            Ok(frame) => Report::new(&frame, lenient, registry),
+            Err(e) => Report::from_error(e),
+        });
+    }
+    reports
+}
+
+fn report_trailing(framer: &TelegramFramer) {
+    if framer.pending() > 0 {
+        eprintln!(
+            "{} trailing bytes without a complete telegram",
+            framer.pending()
+        );
+    }
+}
+
+fn read_input(path: &Path) -> Result<Vec<u8>> {
+    if path == Path::new("-") {
+        let mut content = Vec::new();
+        io::stdin().read_to_end(&mut content)?;
+        Ok(content)
+    } else {
+        fs::read(path).with_context(|| format!("could not read {}", path.display()))
+    }
+}
+
+#[derive(Default)]
+struct Summary {
+    total: usize,
+    telegrams: BTreeMap<String, usize>,
+    failures: BTreeMap<&'static str, usize>,
+}
+
+impl Summary {
+    fn add(&mut self, report: &Report, registry: &Registry) {
+        self.total += 1;
+        if let Some(t) = &report.telegram {
+            let name = registry
+                .lookup(t.device_type, t.command, t.subcommand)
+                .map_or_else(String::new, |def| format!(" {}", def.name));
+            let key = format!("{:?} {}{}", t.command, t.subcommand, name);
+            *self.telegrams.entry(key).or_default() += 1;
+        }
+        if report.status != "ok" {
+            *self.failures.entry(report.status).or_default() += 1;
+        }
+    }
+
+    /// Printed to stderr, so json and csv output on stdout stay machine readable.
+    fn print(&self) {
+        eprintln!("\n{} frames", self.total);
+        for (key, count) in &self.telegrams {
+            eprintln!("  {:>5}  {}", count, key);
+        }
+        let failed: usize = self.failures.values().sum();
+        eprintln!("{} failures", failed);
+        for (status, count) in &self.failures {
+            eprintln!("  {:>5}  {}", count, status);
+        }
+    }
+}
+
+/// Outcome of decoding one frame, in the stable schema of the json and csv output.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub line: Option<usize>,
+    pub raw: String,
+    pub status: &'static str,
+    pub error: Option<String>,
+    pub crc: Option<String>,
+    pub declared_length: Option<u8>,
+    pub telegram: Option<Telegram>,
+    pub decoded: Option<String>,
+}
+
+impl Report {
+    pub fn new(bytes: &[u8], lenient: bool, registry: &Registry) -> Self {
+        let decoded = if lenient {
+            Telegram::from_bytes_lenient(bytes)
+        } else {
+            Telegram::from_bytes(bytes).map(|t| (t, None))
+        };
+        let (telegram, error) = match decoded {
+            Ok((telegram, warning)) => (Some(telegram), warning),
+            Err(e) => (None, Some(e)),
+        };
+
+        Report {
+            line: None,
+            raw: format_hex(bytes),
+            status: error.as_ref().map_or("ok", status),
+            error: error.map(|e| e.to_string()),
+            crc: (bytes.len() >= 2).then(|| {
+                let crc = u16::from_le_bytes([bytes[bytes.len() - 2], bytes[bytes.len() - 1]]);
+                format!("{:#06x}", crc)
+            }),
+            declared_length: bytes.get(HEADER_LEN - 1).copied(),
+            decoded: telegram.as_ref().map(|t| registry.describe(t)),
+            telegram,
+        }
+    }
+
+    /// A frame the framer had to skip, its bytes are not available.
+    pub fn from_error(error: TelegramError) -> Self {
+        Report {
+            line: None,
+            raw: String::new(),
+            status: status(&error),
+            error: Some(error.to_string()),
+            crc: None,
+            declared_length: None,
+            telegram: None,
+            decoded: None,
+        }
+    }
+
+    pub fn invalid_hex(token: &str) -> Self {
+        Report {
+            line: None,
+            raw: String::new(),
+            status: "invalid_hex",
+            error: Some(format!("invalid hex byte {}", token)),
+            crc: None,
+            declared_length: None,
+            telegram: None,
+            decoded: None,
+        }
+    }
+
+    pub fn at_line(mut self, line: usize) -> Self {
+        self.line = Some(line);
+        self
+    }
+}
+
+fn status(error: &TelegramError) -> &'static str {
+    match error {
+        TelegramError::TooShort(_) => "too_short",
+        TelegramError::TooLong(_) => "too_long",
+        TelegramError::LengthMismatch { .. } => "length_mismatch",
+        TelegramError::BadChecksum { .. } => "bad_checksum",
+        TelegramError::UnknownCommand(_) => "unknown_command",
+    }
+}
+
+/// Flattened [`Report`], csv can't represent the nested telegram.
+#[derive(Serialize)]
+struct CsvRow<'a> {
+    line: Option<usize>,
+    raw: &'a str,
+    status: &'a str,
+    error: Option<&'a str>,
+    crc: Option<&'a str>,
+    declared_length: Option<u8>,
+    device_type: Option<u16>,
+    serial_number: Option<u32>,
+    command: Option<String>,
+    subcommand: Option<u8>,
+    data: Option<String>,
+    decoded: Option<&'a str>,
+}
+
+impl<'a> CsvRow<'a> {
+    fn new(report: &'a Report) -> Self {
+        let telegram = report.telegram.as_ref();
+        CsvRow {
+            line: report.line,
+            raw: &report.raw,
+            status: report.status,
+            error: report.error.as_deref(),
+            crc: report.crc.as_deref(),
+            declared_length: report.declared_length,
+            device_type: telegram.map(|t| t.device_type),
+            serial_number: telegram.map(|t| t.serial_number),
+            command: telegram.map(|t| format!("{:?}", t.command)),
+            subcommand: telegram.map(|t| t.subcommand),
+            data: telegram.map(|t| format_hex(&t.data)),
+            decoded: report.decoded.as_deref(),
+        }
+    }
+}
+
+pub struct Printer<'a> {
+    output: OutputFormat,
+    registry: &'a Registry,
+    csv: Option<csv::Writer<Stdout>>,
+}
+
+impl<'a> Printer<'a> {
+    pub fn new(output: OutputFormat, registry: &'a Registry) -> Self {
+        let csv =
+            matches!(output, OutputFormat::Csv).then(|| csv::Writer::from_writer(io::stdout()));
+        Printer {
+            output,
+            registry,
+            csv,
+        }
+    }
+
+    pub fn print(&mut self, report: &Report) -> Result<()> {
+        if let (Some(line), OutputFormat::Text | OutputFormat::Rust) = (report.line, self.output) {
+            print!("{}: ", format!("line {}", line).bold());
+            if report.telegram.is_some() {
+                println!();
+            }
+        }
+        match self.output {
+            OutputFormat::Text => match &report.telegram {
+                Some(telegram) => {
+                    if let Some(warning) = &report.error {
+                        println!("{}: {}", "Warning".yellow(), warning);
+                    }
+                    println!("{}", self.registry.display(telegram));
+                }
+                None => println!("Failed to decode: {}", report.error.as_deref().unwrap_or("")),
+            },
+            OutputFormat::Rust => match &report.telegram {
+                Some(telegram) => println!(
                     "Telegram {{ \n      device_type: {},\n      serial_number: {},\n      command: Command::{:?},\n      subcommand: {},\n      data: vec!{:?}\n}}",
-                    command.device_type,
-                    command.serial_number,
-                    command.command,
-                    command.subcommand,
-                    command.data.as_slice(),
-                )
-            } else {
-                println!(
-                    "device type: {}\nserial number: {}\ncommand: {:?}\nsubcommand: {}\ndata: {:?}",
-                    command.device_type,
-                    command.serial_number,
-                    command.command,
-                    command.subcommand,
-                    command.data.as_slice()
-                )
+                    telegram.device_type,
+                    telegram.serial_number,
+                    telegram.command,
+                    telegram.subcommand,
+                    telegram.data.as_slice(),
+                ),
+                None => println!("Failed to decode: {}", report.error.as_deref().unwrap_or("")),
+            },
+            OutputFormat::Json => println!("{}", serde_json::to_string(report)?),
+            OutputFormat::Csv => {
+                let row = CsvRow::new(report);
+                self.csv.as_mut().expect("csv writer").serialize(row)?;
             }
         }
-        Err(e) => println!("Failed to decode: {}", e),
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(csv) = self.csv.as_mut() {
+            csv.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(input: &str, input_format: InputFormat) -> Vec<Report> {
+        let registry = Registry::builtin();
+        decode_input(input.as_bytes().to_vec(), input_format, false, &registry).unwrap()
+    }
+
+    fn lines_and_statuses(reports: &[Report]) -> Vec<(Option<usize>, &str)> {
+        reports.iter().map(|r| (r.line, r.status)).collect()
+    }
+
+    #[test]
+    fn test_lines() {
+        let reports = decode(
+            "0E 92 FF FF FF FF 04 01 CC B1 21\n\
+             # a comment\n\
+             \n\
+             0E 9G FF\n\
+             0x0E,0x92,0xFF,0xFF,0xFF,0xFF,0x04,0x01,0xCC,0xB1,0x22  # corrupted\n\
+             0E 92 FF\n",
+            InputFormat::Lines,
+        );
+        assert_eq!(
+            lines_and_statuses(&reports),
+            [
+                (Some(1), "ok"),
+                (Some(4), "invalid_hex"),
+                (Some(5), "bad_checksum"),
+                (Some(6), "too_short"),
+            ]
+        );
+        assert_eq!(reports[0].telegram.as_ref().unwrap().subcommand, 204);
+        assert_eq!(reports[1].error.as_deref(), Some("invalid hex byte 9G"));
+        assert_eq!(reports[2].crc.as_deref(), Some("0x22b1"));
+    }
+
+    #[test]
+    fn test_hex_dump() {
+        let reports = decode(
+            "0E 92 FF FF FF FF\n\
+             04 01 CC B1 21 0E 92\n\
+             zz\n\
+             FF FF FF FF 04 01 CC B1 22 0E 92 FF FF FF FF 04 01 CC B1 21\n",
+            InputFormat::Hex,
+        );
+        assert_eq!(
+            lines_and_statuses(&reports),
+            [
+                (Some(2), "ok"),
+                (Some(3), "invalid_hex"),
+                (Some(4), "bad_checksum"),
+                (Some(4), "ok"),
+            ]
+        );
+        assert_eq!(reports[2].raw, "");
+
+        let binary = parse_hex("0E 92 FF FF FF FF 04 01 CC B1 21").unwrap();
+        let registry = Registry::builtin();
+        let reports = decode_input(binary, InputFormat::Binary, false, &registry).unwrap();
+        assert_eq!(lines_and_statuses(&reports), [(None, "ok")]);
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let reports = decode(
+            "0E 92 FF FF FF FF 06 01 CC 01 02 4D D9\n0E 9G\n",
+            InputFormat::Lines,
+        );
+        let json = serde_json::to_value(&reports[0]).unwrap();
+        let keys: Vec<&str> = json
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                "crc",
+                "declared_length",
+                "decoded",
+                "error",
+                "line",
+                "raw",
+                "status",
+                "telegram"
+            ]
+        );
+        assert_eq!(
+            json["telegram"],
+            serde_json::json!({
+                "device_type": 3730,
+                "serial_number": 4294967295u32,
+                "command": "Read",
+                "subcommand": 204,
+                "data": [1, 2],
+            })
+        );
+        assert_eq!(json["crc"], "0xd94d");
+        assert_eq!(json["declared_length"], 6);
+
+        let json = serde_json::to_value(&reports[1]).unwrap();
+        assert_eq!(json["status"], "invalid_hex");
+        assert_eq!(json["telegram"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_csv_schema() {
+        let reports = decode(
+            "0E 92 FF FF FF FF 06 01 CC 01 02 4D D9\n0E 9G\n",
+            InputFormat::Lines,
+        );
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        for report in &reports {
+            writer.serialize(CsvRow::new(report)).unwrap();
+        }
+        let csv = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines[0],
+            "line,raw,status,error,crc,declared_length,device_type,serial_number,command,subcommand,data,decoded"
+        );
+        assert_eq!(
+            lines[1],
+            "1,0E 92 FF FF FF FF 06 01 CC 01 02 4D D9,ok,,0xd94d,6,3730,4294967295,Read,204,01 02,\"Read Greet([1, 2])\""
+        );
+        assert_eq!(lines[2], "2,,invalid_hex,invalid hex byte 9G,,,,,,,,");
     }
-    Ok(())
 }
@@ -1,31 +1,39 @@
+use crate::ble::open_adapter;
+use crate::config::{Config, DeviceSelector};
 use anyhow::Result;
 use dialoguer::{theme::ColorfulTheme, Confirm, FuzzySelect};
 
-pub async fn main() -> Result<()> {
+pub async fn main(config: Config) -> Result<()> {
     let session = bluer::Session::new().await?;
-    let adapter = session.default_adapter().await?;
+    let adapter = open_adapter(&session, config.adapter()).await?;
     adapter.set_powered(true).await?;
     let devices = adapter.device_addresses().await.unwrap();
 
     let mut options: Vec<String> = Vec::new();
+    let mut names: Vec<Option<String>> = Vec::new();
     for a in &devices {
-        let name = adapter
-            .device(*a)
-            .unwrap()
-            .name()
-            .await
-            .unwrap_or(None)
-            .unwrap_or_else(|| "Unknown".to_string());
-        options.extend_from_slice(&[format!("{}, {}", a, name)]);
+        let name = adapter.device(*a).unwrap().name().await.unwrap_or(None);
+        options.extend_from_slice(&[format!("{}, {}", a, name.as_deref().unwrap_or("Unknown"))]);
+        names.push(name);
     }
 
-    let res = FuzzySelect::with_theme(&ColorfulTheme::default())
-        .with_prompt("Select device")
-        .items(&options)
-        .interact()
-        .unwrap();
+    // A device given with --device is selected directly, when it is known to the adapter.
+    let configured = match config.device_or_interactive()? {
+        DeviceSelector::Address(addr) => devices.iter().position(|a| *a == addr),
+        DeviceSelector::Name(name) => names.iter().position(|n| n.as_ref() == Some(&name)),
+        DeviceSelector::Interactive => None,
+    };
+    let res = match configured {
+        Some(res) => res,
+        None => FuzzySelect::with_theme(&ColorfulTheme::default())
+            .with_prompt("Select device")
+            .items(&options)
+            .interact()
+            .unwrap(),
+    };
 
     let selected_device = devices[res];
+    println!("{}", options[res]);
 
     let res = Confirm::with_theme(&ColorfulTheme::default())
         .with_prompt("Remove?")
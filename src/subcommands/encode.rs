@@ -0,0 +1,127 @@
+use crate::args::{parse_number, EncodeFormat};
+use crate::ble::registry::Registry;
+use crate::ble::telegram::{format_hex, parse_hex, Command, Telegram};
+use anyhow::{anyhow, Result};
+use std::path::PathBuf;
+
+pub fn main(
+    device_type: u16,
+    serial_number: u32,
+    command: Command,
+    subcommand: String,
+    data: String,
+    output: EncodeFormat,
+    registry: Option<PathBuf>,
+) -> Result<()> {
+    let registry = Registry::load(registry.as_deref())?;
+    let bytes = encode(
+        device_type,
+        serial_number,
+        command,
+        &subcommand,
+        &data,
+        &registry,
+    )?;
+
+    let formatted: Vec<String> = bytes.iter().map(|b| format!("0x{:02X}", b)).collect();
+    match output {
+        EncodeFormat::Hex => println!("{}", format_hex(&bytes)),
+        EncodeFormat::C => println!(
+            "const uint8_t telegram[{}] = {{ {} }};",
+            bytes.len(),
+            formatted.join(", ")
+        ),
+        EncodeFormat::Rust => println!(
+            "const TELEGRAM: [u8; {}] = [{}];",
+            bytes.len(),
+            formatted.join(", ")
+        ),
+    }
+
+    Ok(())
+}
+
+/// Builds the telegram bytes, `subcommand` is a number or the name of a `command` subcommand
+/// in the registry.
+fn encode(
+    device_type: u16,
+    serial_number: u32,
+    command: Command,
+    subcommand: &str,
+    data: &str,
+    registry: &Registry,
+) -> Result<Vec<u8>> {
+    let subcommand = match parse_number::<u8>(subcommand) {
+        Ok(subcommand) => subcommand,
+        Err(_) => {
+            registry
+                .find(device_type, command, subcommand)
+                .ok_or_else(|| anyhow!("unknown {:?} subcommand {}", command, subcommand))?
+                .subcommand
+        }
+    };
+
+    let telegram = Telegram {
+        device_type,
+        serial_number,
+        command,
+        subcommand,
+        data: parse_hex(data).map_err(|t| anyhow!("invalid hex byte {}", t))?,
+    };
+    Ok(telegram.to_bytes()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crc::{Crc, CRC_16_MODBUS};
+
+    #[test]
+    fn test_round_trip() {
+        const CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_MODBUS);
+        let registry = Registry::builtin();
+        for (command, name, data, described) in [
+            (Command::Read, "greet", "", "Read Greet"),
+            (
+                Command::Read,
+                "ReadRegister",
+                "00 31",
+                "Read ReadRegister(addr=0x0031)",
+            ),
+            (
+                Command::Write,
+                "changebaudrate",
+                "00 00 25 80",
+                "Write ChangeBaudrate(baudrate=9600)",
+            ),
+        ] {
+            let bytes = encode(3730, 0xFFFFFFFF, command, name, data, &registry).unwrap();
+            let (body, crc) = bytes.split_at(bytes.len() - 2);
+            assert_eq!(crc, CRC.checksum(body).to_le_bytes());
+
+            let telegram = Telegram::from_bytes(&bytes).unwrap();
+            assert_eq!(telegram.device_type, 3730);
+            assert_eq!(telegram.command, command);
+            assert_eq!(registry.describe(&telegram), described);
+        }
+
+        assert_eq!(
+            format_hex(&encode(3730, 0xFFFFFFFF, Command::Read, "greet", "", &registry).unwrap()),
+            "0E 92 FF FF FF FF 04 01 CC B1 21"
+        );
+    }
+
+    #[test]
+    fn test_lookup_by_command() {
+        let registry = Registry::builtin();
+        let read = encode(1, 2, Command::Read, "ReadRegister", "00 31", &registry).unwrap();
+        assert_eq!(read[8], 210);
+        // ChangeBaudrate shares subcommand 210, but only as a write.
+        assert!(encode(1, 2, Command::Read, "ChangeBaudrate", "", &registry).is_err());
+        assert!(encode(1, 2, Command::Execute, "greet", "", &registry).is_err());
+        assert_eq!(
+            encode(1, 2, Command::Execute, "0xCC", "", &registry).unwrap()[8],
+            204
+        );
+    }
+}
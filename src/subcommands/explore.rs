@@ -1,83 +1,31 @@
-use std::time::Duration;
+use std::{path::PathBuf, time::Duration};
 
-use anyhow::Result;
+use crate::ble::{
+    find_selected_device, open_adapter,
+    recorder::{Direction, Recorder},
+};
+use crate::config::Config;
+use anyhow::{anyhow, Result};
 use bluer::{
-    gatt::{
-        remote::{Characteristic, CharacteristicWriteRequest, Service},
-        CharacteristicFlags,
-    },
-    Device, DiscoveryFilter, DiscoveryTransport,
+    gatt::remote::{Characteristic, CharacteristicWriteRequest, Service},
+    Device,
 };
 use dialoguer::{theme::ColorfulTheme, FuzzySelect};
 use futures::stream;
 use futures::{pin_mut, StreamExt};
-use tokio::{
-    io::{self, AsyncBufReadExt},
-    select,
-    time::{sleep, timeout},
-};
+use tokio::time::{sleep, timeout};
+
+pub async fn main(config: Config, record: Option<PathBuf>) -> Result<()> {
+    let mut recorder = record.as_deref().map(Recorder::create).transpose()?;
 
-pub async fn main() -> Result<()> {
     let session = bluer::Session::new().await?;
-    let adapter = session.default_adapter().await?;
+    let adapter = open_adapter(&session, config.adapter()).await?;
     adapter.set_powered(true).await?;
 
-    let filter = DiscoveryFilter {
-        transport: DiscoveryTransport::Le,
-        ..Default::default()
-    };
-    adapter.set_discovery_filter(filter).await?;
-
-    let device_events = adapter.discover_devices().await?;
-    pin_mut!(device_events);
-
-    let mut devices: Vec<Device> = Vec::new();
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
-
-    println!("To stop scan, press <ENTER>");
-
-    loop {
-        select!(
-            Some(dev_event) = device_events.next() => {
-                match dev_event {
-                    bluer::AdapterEvent::DeviceAdded(dev_addr) => {
-                        let dev = adapter.device(dev_addr)?;
-                        let name = dev.name().await?;
-                        devices.push(dev);
-                        println!("  Device added {}, {:?}", dev_addr, name);
-                    }
-                    bluer::AdapterEvent::DeviceRemoved(dev_addr) => {
-                        let dev = adapter.device(dev_addr)?;
-                        let name = dev.name().await;
-                        devices.retain(|v| v.address() != dev_addr);
-                        println!("  Device removed {}, {:?}", dev_addr, name);
-                    }
-                    _ => {}
-                }
-            },
-            Ok(Some(_)) = stdin.next_line() => break,
-        );
-    }
-
-    let options: Vec<String> = stream::iter(devices.clone())
-        .then(|d| async move {
-            let name = d
-                .name()
-                .await
-                .unwrap_or(None)
-                .unwrap_or_else(|| "Unknown".to_string());
-            format!("{}, {}", d.address(), name)
-        })
-        .collect()
-        .await;
-
-    let res = FuzzySelect::with_theme(&ColorfulTheme::default())
-        .with_prompt("Select device")
-        .items(&options)
-        .interact()
-        .unwrap();
-
-    let dev = devices[res].clone();
+    let device = config.device_or_interactive()?;
+    let dev = find_selected_device(&adapter, &device, config.scan_timeout())
+        .await?
+        .ok_or_else(|| anyhow!("Couldn't find device {:?}", device))?;
 
     loop {
         let mut options: Vec<&str> = Vec::new();
@@ -125,7 +73,7 @@ pub async fn main() -> Result<()> {
                     }
                 }
             },
-            "Services" => services_menu(&dev).await,
+            "Services" => services_menu(&dev, config.timeout(), recorder.as_mut()).await,
             "Forget" => adapter.remove_device(dev.address()).await?,
             "Info" => print_dev_info(&dev).await,
             "Quit" => break,
@@ -137,7 +85,7 @@ pub async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn services_menu(dev: &Device) {
+async fn services_menu(dev: &Device, response_timeout: Duration, recorder: Option<&mut Recorder>) {
     let services: Vec<Service> = dev.services().await.unwrap();
     let mut options: Vec<String> = stream::iter(services.clone())
         .then(|s| async move { format!("{}", s.uuid().await.unwrap()) })
@@ -151,11 +99,11 @@ async fn services_menu(dev: &Device) {
         .unwrap();
 
     if res != services.len() {
-        chars_menu(&services[res]).await;
+        chars_menu(&services[res], response_timeout, recorder).await;
     }
 }
 
-async fn chars_menu(serv: &Service) {
+async fn chars_menu(serv: &Service, response_timeout: Duration, recorder: Option<&mut Recorder>) {
     let chars = serv.characteristics().await.unwrap();
     let mut options: Vec<String> = stream::iter(chars.clone())
         .then(|s| async move { format!("{}", s.uuid().await.unwrap()) })
@@ -169,11 +117,23 @@ async fn chars_menu(serv: &Service) {
         .unwrap();
 
     if res != chars.len() {
-        char_menu(&chars[res]).await;
+        char_menu(&chars[res], response_timeout, recorder).await;
     }
 }
 
-async fn char_menu(char: &Characteristic) {
+async fn char_menu(
+    char: &Characteristic,
+    response_timeout: Duration,
+    mut recorder: Option<&mut Recorder>,
+) {
+    let uuid = char.uuid().await.unwrap();
+    let mut record = |direction: Direction, bytes: &[u8]| {
+        if let Some(recorder) = recorder.as_mut() {
+            if let Err(e) = recorder.telegram(direction, uuid, bytes) {
+                eprintln!("Recording failed: {}", e);
+            }
+        }
+    };
     let options = vec!["Write", "Read", "Read Response", "Back"];
 
     let write_req = CharacteristicWriteRequest {
@@ -207,12 +167,20 @@ async fn char_menu(char: &Characteristic) {
                     })
                     .collect();
                 char.write_ext(bytes.as_slice(), &write_req).await.unwrap();
+                record(Direction::Tx, &bytes);
+            }
+            "Read" => {
+                let value = char.read().await.unwrap();
+                record(Direction::Rx, &value);
+                println!("{:?}", value);
+            }
+            "Read Response" => {
+                let response = timeout(response_timeout, notify.next()).await;
+                if let Ok(Some(value)) = &response {
+                    record(Direction::Rx, value);
+                }
+                println!("response: {:?}", response);
             }
-            "Read" => println!("{:?}", char.read().await.unwrap()),
-            "Read Response" => println!(
-                "response: {:?}",
-                timeout(Duration::from_millis(1500), notify.next()).await
-            ),
             "Back" => break,
             _ => {}
         }
@@ -0,0 +1,40 @@
+use crate::ble::client::Client;
+use crate::ble::connection::{Channels, Connection};
+use crate::ble::register_map::RegisterMap;
+use crate::ble::telegram::Telegram;
+use crate::config::Config;
+use anyhow::Result;
+use colored::Colorize;
+use std::path::PathBuf;
+
+pub async fn read(config: Config, register: String, map: PathBuf) -> Result<()> {
+    let map = RegisterMap::from_file(&map)?;
+    let register = map.resolve(&register)?;
+
+    let reply = transceive(&config, map.read_telegram(&register)).await?;
+    println!("{}", map.decode_reply(&register, &reply)?);
+
+    Ok(())
+}
+
+pub async fn write(config: Config, register: String, value: f64, map: PathBuf) -> Result<()> {
+    let map = RegisterMap::from_file(&map)?;
+    let register = map.resolve(&register)?;
+
+    let reply = transceive(&config, map.write_telegram(&register, value)?).await?;
+    println!("{}", map.decode_reply(&register, &reply)?);
+
+    Ok(())
+}
+
+/// Sends a single telegram to the testbench characteristic and waits for its reply.
+async fn transceive(config: &Config, request: Telegram) -> Result<Telegram> {
+    let mut connection = Connection::open(config, Channels::TESTBENCH).await?;
+    let mut client = Client::new(&mut connection.transport, config.request_options());
+
+    println!("{}: {}", "Request".blue(), request);
+    let reply = client.request(&request).await;
+
+    connection.disconnect().await?;
+    Ok(reply?)
+}
@@ -0,0 +1,76 @@
+use crate::args::{ReplayArgs, SummaryFormat};
+use crate::ble::client::Client;
+use crate::ble::connection::{Channels, Connection};
+use crate::ble::recorder::read_records;
+use crate::ble::registry::Registry;
+use crate::ble::replay::{self, Timing};
+use crate::ble::report::TestReport;
+use crate::ble::stats::RunSummary;
+use crate::ble::telegram_sequence::{EventSequence, Outcome};
+use crate::ble::transport::{TcpTransport, TelegramTransport};
+use crate::config::Config;
+use crate::subcommands::run::{check_failures, print_summary};
+use anyhow::Result;
+use tokio::time::{Duration, Instant};
+
+pub async fn main(config: Config, args: ReplayArgs) -> Result<()> {
+    let registry = Registry::load(args.registry.as_deref())?;
+
+    let records = read_records(&args.file)?;
+    let timing = match args.delay {
+        Some(delay) => Timing::Fixed(Duration::from_millis(delay)),
+        None => Timing::Recorded { speed: args.speed },
+    };
+    let sequence = replay::sequence(&records, timing)?;
+    if let SummaryFormat::Text = args.summary {
+        println!(
+            "Replaying {} requests of {} records",
+            sequence.steps.len(),
+            records.len()
+        );
+    }
+
+    let start = Instant::now();
+    let (outcomes, duration, stats) = match &args.tcp {
+        Some(addr) => {
+            let transport = TcpTransport::connect(addr, args.framing.stream_framing()).await?;
+            let mut client = Client::new(transport, config.request_options());
+            let outcomes = replay(&mut client, &sequence, &registry, args.summary).await;
+            let (duration, stats) = (start.elapsed(), client.stats());
+            client.into_inner().close().await?;
+            (outcomes, duration, stats)
+        }
+        None => {
+            let mut connection = Connection::open(&config, Channels::TESTBENCH).await?;
+            let start = Instant::now();
+            let mut client = Client::new(&mut connection.transport, config.request_options());
+            let outcomes = replay(&mut client, &sequence, &registry, args.summary).await;
+            let (duration, stats) = (start.elapsed(), client.stats());
+            connection.disconnect().await?;
+            (outcomes, duration, stats)
+        }
+    };
+    let stats = RunSummary::new(&sequence.steps, &outcomes, stats, duration);
+
+    print_summary(&stats, args.summary)?;
+    if let Some(path) = &args.report {
+        let name = args
+            .file
+            .file_stem()
+            .map_or("replay".into(), |s| s.to_string_lossy());
+        TestReport::new(&name, &sequence.steps, &outcomes, duration).write(path)?;
+    }
+    check_failures(&stats)
+}
+
+async fn replay<T: TelegramTransport>(
+    client: &mut Client<T>,
+    sequence: &EventSequence,
+    registry: &Registry,
+    summary: SummaryFormat,
+) -> Vec<Outcome> {
+    match summary {
+        SummaryFormat::Text => sequence.send(client, registry).await,
+        SummaryFormat::Json => sequence.run(client, |_, _, _| {}).await,
+    }
+}
@@ -1,90 +1,104 @@
-use crate::ble::telegram::Telegram;
+use crate::args::{RunArgs, SummaryFormat};
+use crate::ble::client::Client;
+use crate::ble::connection::{Channels, Connection};
+use crate::ble::registry::Registry;
+use crate::ble::report::TestReport;
+use crate::ble::script::Script;
+use crate::ble::stats::RunSummary;
+use crate::ble::telegram::{Command, Telegram};
 use crate::ble::telegram_sequence::EventSequence;
-use crate::ble::{find_characteristic, find_service, prefab};
-use crate::ble::{find_device_name, find_device_select};
-use anyhow::Result;
-use bluer::Uuid;
-use dotenv::dotenv;
-use std::{env, str::FromStr};
-use tokio::time::{sleep, timeout, Duration};
+use crate::config::Config;
+use anyhow::{bail, Result};
+use colored::Colorize;
+use tokio::time::{sleep, Duration, Instant};
 
-pub async fn main(send_amount: usize, delay: u64) -> Result<()> {
-    // Get data from .env
-    dotenv()?;
-    let dev_name = env::var("DEVICE_NAME").expect("DEVICE_NAME not found in .env");
-    let service_uuid =
-        Uuid::from_str(&env::var("SERVICE_UUID").expect("SERVICE_UUID not found in .env")).unwrap();
-    let char_uuid =
-        Uuid::from_str(&env::var("TESTBENCH").expect("CHARACTERISTIC not found in .env")).unwrap();
+pub async fn main(config: Config, args: RunArgs) -> Result<()> {
+    let RunArgs {
+        iterations,
+        delay,
+        script,
+        variables,
+        registry,
+        summary,
+        report,
+        record,
+    } = args;
+    let registry = Registry::load(registry.as_deref())?;
 
-    println!(
-        "SERVICE: {:?}\nCHARACTER: {:?}\ndelay: {:?}",
-        service_uuid, char_uuid, delay
-    );
-
-    // Get Device->Service->Character for communication
-    let session = bluer::Session::new().await?;
-    let adapter = session.default_adapter().await?;
-    adapter.set_powered(true).await?;
-    println!("addr: {}", adapter.address().await.unwrap());
-
-    let dev = timeout(
-        Duration::from_secs(100),
-        find_device_name(&adapter, dev_name),
-        // find_device_select(&adapter),
-    )
-    .await??
-    .expect("Couldn't find device address");
-
-    if !dev.is_connected().await? {
-        println!("connecting...");
-        dev.connect().await?;
-    }
-    println!("Connected");
-
-    if !dev.is_paired().await? {
-        println!("Pairing...");
-        match dev.pair().await {
-            Ok(_) => println!("Paired"),
-            Err(e) => {
-                println!("Failed to pair: {}", e);
-                dev.disconnect().await.unwrap();
-                return Ok(());
+    let name = script
+        .as_deref()
+        .and_then(|p| p.file_stem())
+        .map_or("run".into(), |s| s.to_string_lossy());
+    let sequence = match &script {
+        Some(path) => {
+            let mut script = Script::from_file(path)?;
+            for variable in &variables {
+                script.set_variable(variable)?;
             }
+            if let Some(iterations) = iterations {
+                script.repeat = iterations;
+            }
+            if let Some(delay) = delay {
+                script.delay_ms = delay;
+            }
+            script.compile(&registry)?
         }
-    }
-
-    sleep(Duration::from_secs(1)).await;
+        None => EventSequence::new(
+            vec![
+                Telegram {
+                    device_type: 0xffff,
+                    serial_number: 0xffffffff,
+                    command: Command::Read,
+                    subcommand: 101,
+                    data: vec![],
+                };
+                iterations.unwrap_or_default()
+            ],
+            Duration::from_millis(delay.unwrap_or_default()),
+        ),
+    };
+    let mut connection = Connection::open(&config, Channels::TESTBENCH).await?;
 
-    // let sequence = prefab::get_sequence(send_amount, Duration::from_millis(delay));
-    let sequence = EventSequence {
-        sequence: vec![
-            Telegram {
-                device_type: 0xffff,
-                serial_number: 0xffffffff,
-                command: crate::ble::telegram::Command::Read,
-                subcommand: 101,
-                data: vec![], //vec![0xAA; 244],
-            };
-            send_amount
-        ],
-        delay: Duration::from_millis(delay),
+    let transport = connection.recorded(&config, record.as_deref())?;
+    let mut client = Client::new(transport, config.request_options());
+    let start = Instant::now();
+    let outcomes = match summary {
+        SummaryFormat::Text => sequence.send(&mut client, &registry).await,
+        SummaryFormat::Json => sequence.run(&mut client, |_, _, _| {}).await,
     };
-    println!(">>{:?}<<", sequence.sequence[0].to_bytes().unwrap());
+    let duration = start.elapsed();
+    let stats = RunSummary::new(&sequence.steps, &outcomes, client.stats(), duration);
 
-    if let Some(service) = find_service(&dev, service_uuid).await? {
-        println!("Found service");
-        if let Some(char) = find_characteristic(&service, char_uuid).await? {
-            println!("  Found Characteristic");
+    sleep(Duration::from_millis(100)).await;
 
-            sequence.send(char).await?;
-        }
-    }
+    connection.disconnect().await?;
 
-    sleep(Duration::from_millis(100)).await;
+    print_summary(&stats, summary)?;
+    if let Some(path) = report {
+        TestReport::new(&name, &sequence.steps, &outcomes, duration).write(&path)?;
+    }
+    check_failures(&stats)
+}
 
-    dev.disconnect().await?;
-    println!("disconnected");
+pub fn print_summary(stats: &RunSummary, summary: SummaryFormat) -> Result<()> {
+    match summary {
+        SummaryFormat::Text => println!("\n{}\n{}", "Summary".bold(), stats),
+        SummaryFormat::Json => println!("{}", serde_json::to_string_pretty(stats)?),
+    }
+    Ok(())
+}
 
+/// Fails when a response didn't meet its expectations, a request failed or checks weren't run
+/// because the link closed or failed, after the results were reported.
+pub fn check_failures(stats: &RunSummary) -> Result<()> {
+    if stats.failures() > 0 {
+        bail!(
+            "{} of {} checks failed, {} requests failed, {} checks skipped",
+            stats.check_failures,
+            stats.checks,
+            stats.errors,
+            stats.skipped_checks
+        );
+    }
     Ok(())
 }
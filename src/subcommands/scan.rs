@@ -1,74 +1,16 @@
-use anyhow::Result;
-use bluer::{Device, DiscoveryFilter, DiscoveryTransport};
-use dialoguer::{theme::ColorfulTheme, FuzzySelect};
-use futures::stream;
-use futures::{pin_mut, StreamExt};
-use tokio::{
-    io::{self, AsyncBufReadExt},
-    select,
-};
+use crate::ble::{find_selected_device, open_adapter};
+use crate::config::Config;
+use anyhow::{anyhow, Result};
 
-pub async fn main() -> Result<()> {
+pub async fn main(config: Config) -> Result<()> {
     let session = bluer::Session::new().await?;
-    let adapter = session.default_adapter().await?;
+    let adapter = open_adapter(&session, config.adapter()).await?;
     adapter.set_powered(true).await?;
 
-    let filter = DiscoveryFilter {
-        transport: DiscoveryTransport::Le,
-        ..Default::default()
-    };
-    adapter.set_discovery_filter(filter).await?;
-
-    let device_events = adapter.discover_devices().await?;
-    pin_mut!(device_events);
-
-    let mut devices: Vec<Device> = Vec::new();
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
-
-    println!("To stop scan, press <ENTER>");
-
-    loop {
-        select!(
-            Some(dev_event) = device_events.next() => {
-                match dev_event {
-                    bluer::AdapterEvent::DeviceAdded(dev_addr) => {
-                        let dev = adapter.device(dev_addr)?;
-                        let name = dev.name().await?;
-                        devices.push(dev);
-                        println!("  Device added {}, {:?}", dev_addr, name);
-                    }
-                    bluer::AdapterEvent::DeviceRemoved(dev_addr) => {
-                        let dev = adapter.device(dev_addr)?;
-                        let name = dev.name().await;
-                        devices.retain(|v| v.address() != dev_addr);
-                        println!("  Device removed {}, {:?}", dev_addr, name);
-                    }
-                    _ => {}
-                }
-            },
-            Ok(Some(_)) = stdin.next_line() => break,
-        );
-    }
-
-    let options: Vec<String> = stream::iter(devices.clone())
-        .then(|d| async move {
-            let name = d
-                .name()
-                .await
-                .unwrap_or(None)
-                .unwrap_or_else(|| "Unknown".to_string());
-            format!("{}, {}", d.address(), name)
-        })
-        .collect()
-        .await;
-
-    let res = FuzzySelect::with_theme(&ColorfulTheme::default())
-        .with_prompt("Select device")
-        .items(&options)
-        .interact()
-        .unwrap();
-
-    let device = devices[res].clone();
+    let selector = config.device_or_interactive()?;
+    let device = find_selected_device(&adapter, &selector, config.scan_timeout())
+        .await?
+        .ok_or_else(|| anyhow!("Couldn't find device {:?}", selector))?;
 
     println!(
         "name{:?}
@@ -0,0 +1,17 @@
+use crate::ble::framing::StreamFraming;
+use crate::ble::simulator::{Simulator, SimulatorConfig};
+use anyhow::Result;
+use std::path::PathBuf;
+
+pub async fn main(
+    config: SimulatorConfig,
+    tcp: String,
+    unix: Option<PathBuf>,
+    framing: StreamFraming,
+) -> Result<()> {
+    let simulator = Simulator::new(config);
+    match unix {
+        Some(path) => simulator.listen_unix(&path, framing).await,
+        None => simulator.listen_tcp(&tcp, framing).await,
+    }
+}
@@ -0,0 +1,151 @@
+use crate::args::TcpClientArgs;
+use crate::ble::client::{Client, RequestOptions};
+use crate::ble::registry::Registry;
+use crate::ble::telegram::{format_hex, parse_hex, Telegram};
+use crate::ble::transport::{TcpTransport, TelegramTransport};
+use anyhow::{anyhow, bail, Context, Result};
+use colored::Colorize;
+use tokio::io::{self, AsyncBufReadExt};
+use tokio::time::Duration;
+
+fn parse_telegram(text: &str) -> Result<Telegram> {
+    let bytes = parse_hex(text).map_err(|t| anyhow!("invalid hex byte {}", t))?;
+    Ok(Telegram::from_bytes(&bytes)?)
+}
+
+/// Sends the telegram written on `line` and prints the response, `false` when either failed.
+async fn request_line<T: TelegramTransport>(
+    client: &mut Client<T>,
+    registry: &Registry,
+    line: &str,
+) -> bool {
+    let telegram = match parse_telegram(line) {
+        Ok(t) => t,
+        Err(e) => {
+            println!("{}{}", "Error: ".red(), e);
+            return false;
+        }
+    };
+    println!("{}: {}", "Request".blue(), registry.display(&telegram));
+    let result = client.request(&telegram).await;
+    for stray in client.take_strays() {
+        println!("{}: {}", "Stray".yellow(), registry.display(&stray));
+    }
+    match result {
+        Ok(response) => {
+            println!(
+                "{}: {}\n{}",
+                "Response".green(),
+                format_hex(&response.to_bytes().unwrap_or_default()),
+                registry.display(&response)
+            );
+            true
+        }
+        Err(e) => {
+            println!("{}{}", "Error: ".red(), e);
+            false
+        }
+    }
+}
+
+pub async fn main(args: TcpClientArgs) -> Result<()> {
+    let registry = Registry::load(args.registry.as_deref())?;
+    let transport = TcpTransport::connect(&args.connect, args.framing.stream_framing())
+        .await
+        .with_context(|| format!("could not connect to {}", args.connect))?;
+    let options = RequestOptions {
+        timeout: Duration::from_millis(args.timeout),
+        ..Default::default()
+    };
+    let mut client = Client::new(transport, options);
+
+    let mut stdin = io::BufReader::new(io::stdin()).lines();
+    let mut arguments = args.telegrams.iter();
+    let (mut sent, mut failed) = (0, 0);
+    loop {
+        let line = if args.telegrams.is_empty() {
+            match stdin.next_line().await? {
+                Some(line) => line,
+                None => break,
+            }
+        } else {
+            match arguments.next() {
+                Some(text) => text.clone(),
+                None => break,
+            }
+        };
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        sent += 1;
+        if !request_line(&mut client, &registry, line).await {
+            failed += 1;
+        }
+    }
+
+    client.into_inner().close().await?;
+    if failed > 0 {
+        bail!("{} of {} telegrams failed", failed, sent);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::{
+        framing::{Framing, StreamFraming},
+        server::{self, Clients, QUEUE_DEPTH},
+        simulator::{Simulator, SimulatorConfig},
+    };
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        for framing in [Framing::LengthPrefixed, Framing::Hex] {
+            let framing = StreamFraming {
+                request: framing,
+                response: framing,
+            };
+            let mut link = Client::new(
+                Simulator::new(SimulatorConfig::default()).transport(),
+                RequestOptions::default(),
+            );
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let (requests, queue) = mpsc::channel(QUEUE_DEPTH);
+            let registry = Registry::builtin();
+            let clients = Clients::default();
+
+            let lines = async {
+                let transport = TcpTransport::connect(&addr, framing).await.unwrap();
+                let options = RequestOptions {
+                    timeout: Duration::from_millis(200),
+                    ..Default::default()
+                };
+                let mut client = Client::new(transport, options);
+                let mut results = Vec::new();
+                for line in [
+                    "0E 92 FF FF FF FF 04 01 CC B1 21",
+                    "0E 92 FF FF FF FF 04 01 CC B1 22",
+                    "0E 9G",
+                ] {
+                    results.push(request_line(&mut client, &registry, line).await);
+                }
+                results
+            };
+            let results = tokio::select! {
+                r = server::accept(listener, requests.clone(), framing, clients.clone()) => {
+                    panic!("server stopped: {:?}", r.err())
+                }
+                r = server::serve_link(&mut link, queue, &registry, Duration::from_millis(100), &clients) => {
+                    panic!("link stopped: {:?}", r.err())
+                }
+                r = lines => r,
+            };
+            assert_eq!(results, [true, false, false], "{}", framing.request);
+        }
+    }
+}